@@ -0,0 +1,29 @@
+//! Wrappers over the maiq api that record latency of every call.
+
+use chrono::{NaiveDate, Weekday};
+use maiq_api_wrapper as wrapper;
+use maiq_shared::{default::DefaultGroup, Fetch, Snapshot};
+
+use crate::metrics::API_LATENCY;
+
+pub use wrapper::{ApiError, Poll};
+
+pub async fn poll() -> Result<Poll, ApiError> {
+  API_LATENCY.time(&["poll"], wrapper::poll()).await
+}
+
+pub async fn latest(fetch: Fetch) -> Result<Snapshot, ApiError> {
+  API_LATENCY.time(&["latest"], wrapper::latest(fetch)).await
+}
+
+pub async fn groups() -> Result<Vec<String>, ApiError> {
+  API_LATENCY.time(&["groups"], wrapper::groups()).await
+}
+
+pub async fn default(group: &str, weekday: Weekday) -> Result<DefaultGroup, ApiError> {
+  API_LATENCY.time(&["default"], wrapper::default(group, weekday)).await
+}
+
+pub async fn date(date: NaiveDate) -> Result<Snapshot, ApiError> {
+  API_LATENCY.time(&["date"], wrapper::date(date)).await
+}
@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use maiq_shared::utils::time::now;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  db::{Database, Settings},
+  error::BotError,
+};
+
+pub const BACKUP_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+  pub version: u32,
+  pub created: DateTime<Utc>,
+  pub users: Vec<Settings>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+  pub total: usize,
+  pub conflicts: Vec<i64>,
+  pub dry_run: bool,
+}
+
+impl Backup {
+  pub fn from_json(raw: &[u8]) -> Result<Self, BotError> {
+    let backup: Backup = serde_json::from_slice(raw)?;
+    if backup.version > BACKUP_VERSION {
+      return Err(BotError::BackupError(format!(
+        "backup v{} is newer than the latest known v{}",
+        backup.version, BACKUP_VERSION
+      )));
+    }
+    Ok(backup)
+  }
+
+  pub fn to_json(&self) -> Result<Vec<u8>, BotError> {
+    Ok(serde_json::to_vec_pretty(self)?)
+  }
+}
+
+impl std::fmt::Display for ImportReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let mode = match self.dry_run {
+      true => "Dry run",
+      false => "Imported",
+    };
+    write!(f, "{}: {} users, {} conflicting ids", mode, self.total, self.conflicts.len())?;
+    if !self.conflicts.is_empty() {
+      write!(f, ": {:?}", self.conflicts)?;
+    }
+    Ok(())
+  }
+}
+
+pub async fn export(db: &Database) -> Result<Backup, BotError> {
+  let users = db.fetch_all().await?;
+  info!("Exporting {} users", users.len());
+  Ok(Backup { version: BACKUP_VERSION, created: now(), users })
+}
+
+/// Existing users with the same ids are overwritten. In dry-run mode nothing is written, only conflicts are reported.
+pub async fn import(db: &Database, backup: Backup, dry_run: bool) -> Result<ImportReport, BotError> {
+  let existing: HashSet<i64> = db.fetch_all().await?.into_iter().map(|u| u.id).collect();
+  let conflicts = backup
+    .users
+    .iter()
+    .map(|u| u.id)
+    .filter(|id| existing.contains(id))
+    .collect();
+  let report = ImportReport { total: backup.users.len(), conflicts, dry_run };
+
+  if !dry_run {
+    db.import(&backup.users).await?;
+  }
+
+  info!("{}", report);
+  Ok(report)
+}
+
+/// `maiq-bot export <path>` or `maiq-bot import <path> [--dry-run]`
+pub async fn run_cli(db: &Database, args: &[String]) -> Result<(), BotError> {
+  let usage = || BotError::BackupError("usage: maiq-bot export <path> | maiq-bot import <path> [--dry-run]".into());
+  let path = args.get(1).ok_or_else(usage)?;
+
+  match args[0].as_str() {
+    "export" => {
+      let backup = export(db).await?;
+      tokio::fs::write(path, backup.to_json()?)
+        .await
+        .map_err(|err| BotError::BackupError(format!("{}: {}", path, err)))?;
+      info!("Exported {} users to {}", backup.users.len(), path);
+    }
+    "import" => {
+      let dry_run = args.iter().skip(2).any(|a| a == "--dry-run");
+      let raw = tokio::fs::read(path)
+        .await
+        .map_err(|err| BotError::BackupError(format!("{}: {}", path, err)))?;
+      let backup = Backup::from_json(&raw)?;
+      let report = import(db, backup, dry_run).await?;
+      println!("{}", report);
+    }
+    _ => return Err(usage()),
+  }
+
+  Ok(())
+}
@@ -1,8 +1,15 @@
-use teloxide::{payloads::EditMessageTextSetters, requests::Requester, types::CallbackQuery, Bot};
+use teloxide::{
+  payloads::{AnswerCallbackQuerySetters, EditMessageTextSetters},
+  requests::Requester,
+  types::CallbackQuery,
+  Bot,
+};
 
 use crate::{
-  bot::{notifier::send_to_all, BotResult, DEV_ID},
-  db::MongoPool,
+  bot::{format::format_quiet_hours, notifier::send_to_all, BotResult, DEV_ID},
+  db::{ChangeSource, Database},
+  error::BotError,
+  quiet_hours::QuietHours,
 };
 
 pub(super) async fn ok(bot: Bot, q: CallbackQuery) -> BotResult {
@@ -16,26 +23,79 @@ pub(super) async fn delete_message(bot: Bot, q: CallbackQuery) -> BotResult {
   Ok(())
 }
 
-pub(super) async fn send_broadcast(bot: Bot, q: CallbackQuery, mongo: MongoPool) -> BotResult {
+pub(super) async fn send_broadcast(bot: Bot, q: CallbackQuery, db: Database) -> BotResult {
   if q.from.id != *DEV_ID {
     return Ok(());
   }
 
-  let users = mongo.fetch_all_notifiable_ids().await?;
+  let users = db.fetch_all_notifiable_ids().await?;
   let msg = q.message.unwrap();
   bot.delete_message(msg.chat.id, msg.id).await?;
-  send_to_all(&bot, msg.text().unwrap(), users.as_slice()).await;
+  send_to_all(&bot, &db, msg.text().unwrap(), users.as_slice()).await;
   Ok(())
 }
 
-pub(super) async fn select_group(bot: Bot, q: CallbackQuery, mongo: MongoPool, group_name: &str) -> BotResult {
+pub(super) async fn forget_me(bot: Bot, q: CallbackQuery, db: Database, requester: &str) -> BotResult {
+  if q.from.id.0.to_string() != requester {
+    bot
+      .answer_callback_query(q.id)
+      .text("Удалить данные может только тот, кто об этом попросил")
+      .show_alert(true)
+      .await?;
+    return Ok(());
+  }
+
   let message = q.message.unwrap();
-  let mut user = mongo.get_or_new(message.chat.id).await?;
-  user.group = Some(group_name.into());
-  user.is_notifications_enabled = true;
-  mongo.update(&user).await?;
+  db.delete(message.chat.id).await?;
+  bot
+    .edit_message_text(message.chat.id, message.id, "Все твои данные удалены 👋\nЧтобы начать заново, нажми /start")
+    .await?;
+  Ok(())
+}
+
+pub(super) async fn select_group(bot: Bot, q: CallbackQuery, db: Database, group_name: &str) -> BotResult {
+  let message = q.message.unwrap();
+  match db
+    .select_group(message.chat.id, group_name, ChangeSource::Callback)
+    .await
+  {
+    Err(BotError::UserNotFound(_)) => {
+      bot
+        .answer_callback_query(q.id)
+        .text("Сначала нажми /start")
+        .show_alert(true)
+        .await?;
+      return Ok(());
+    }
+    res => res?,
+  }
+
+  bot
+    .edit_message_text(message.chat.id, message.id, format!("Теперь твоя группа: <code>{}</code>", group_name))
+    .parse_mode(teloxide::types::ParseMode::Html)
+    .await?;
+  Ok(())
+}
+
+pub(super) async fn set_quiet_hours(bot: Bot, q: CallbackQuery, db: Database, quiet_hours: Option<QuietHours>) -> BotResult {
+  let message = q.message.unwrap();
+  match db
+    .set_quiet_hours(message.chat.id, quiet_hours, ChangeSource::Callback)
+    .await
+  {
+    Err(BotError::UserNotFound(_)) => {
+      bot
+        .answer_callback_query(q.id)
+        .text("Сначала нажми /start")
+        .show_alert(true)
+        .await?;
+      return Ok(());
+    }
+    res => res?,
+  }
+
   bot
-    .edit_message_text(message.chat.id, message.id, format!("Теперь твоя группа: <code>{}</code>", user.group.unwrap()))
+    .edit_message_text(message.chat.id, message.id, format_quiet_hours(quiet_hours))
     .parse_mode(teloxide::types::ParseMode::Html)
     .await?;
   Ok(())
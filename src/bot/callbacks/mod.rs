@@ -9,7 +9,7 @@ use teloxide::{
   Bot,
 };
 
-use crate::{bot::callbacks::handler::*, db::MongoPool};
+use crate::{bot::callbacks::handler::*, db::Database, quiet_hours::QuietHours};
 
 use super::{BotResult, Dispatch};
 
@@ -25,6 +25,9 @@ pub enum CallbackKind {
   Del,
   SelectGroup(String),
   SendBroadcast,
+  /// Id of the user who asked, only they can confirm. A string since callback data has to be valid utf-8
+  ForgetMe(String),
+  SetQuietHours(Option<QuietHours>),
   Unknown,
 }
 
@@ -39,13 +42,15 @@ impl<T: Into<String>> Callback<T> {
 impl Dispatch for CallbackKind {
   type Kind = CallbackQuery;
 
-  async fn dispatch(&self, bot: Bot, q: Self::Kind, mongo: MongoPool) -> BotResult {
+  async fn dispatch(&self, bot: Bot, q: Self::Kind, db: Database) -> BotResult {
     type K = CallbackKind;
     match self {
       K::Ok => ok(bot, q).await,
       K::Del => delete_message(bot, q).await,
-      K::SelectGroup(group) => select_group(bot, q, mongo, group).await,
-      K::SendBroadcast => send_broadcast(bot, q, mongo).await,
+      K::SelectGroup(group) => select_group(bot, q, db, group).await,
+      K::SendBroadcast => send_broadcast(bot, q, db).await,
+      K::ForgetMe(requester) => forget_me(bot, q, db, requester).await,
+      K::SetQuietHours(quiet_hours) => set_quiet_hours(bot, q, db, *quiet_hours).await,
       K::Unknown => {
         error!("Unknown callback id {} received", q.id);
         bot
@@ -4,7 +4,7 @@ use teloxide::{macros::BotCommands, types::Message, Bot};
 
 use crate::{
   bot::{context::Context, BotResult, Dispatch},
-  db::MongoPool,
+  db::Database,
   error::ReadableError,
 };
 
@@ -47,6 +47,15 @@ pub enum Command {
   #[command(description = "Установить имя")]
   SetTeacher(String),
 
+  #[command(description = "Обновлять прошлое уведомление вместо нового")]
+  ToggleEditMode,
+
+  #[command(description = "Тихие часы для уведомлений")]
+  QuietHours(String),
+
+  #[command(description = "Удалить все свои данные")]
+  ForgetMe,
+
   #[command(description = "Старт")]
   Start,
 }
@@ -55,9 +64,9 @@ pub enum Command {
 impl Dispatch for Command {
   type Kind = Message;
 
-  async fn dispatch(&self, bot: Bot, kind: Self::Kind, mongo: MongoPool) -> BotResult {
+  async fn dispatch(&self, bot: Bot, kind: Self::Kind, db: Database) -> BotResult {
     info!("Command {:?} from {} [{}]", self, kind.from().unwrap().full_name(), kind.from().unwrap().id.0);
-    let ctx = Context::new(bot, kind, mongo);
+    let ctx = Context::new(bot, kind, db);
 
     let res = match self {
       Command::Start => ctx.start().await,
@@ -73,6 +82,10 @@ impl Dispatch for Command {
       Command::TeacherToday => ctx.reply_teacher_timetable(Fetch::Today).await,
       Command::TeacherNext => ctx.reply_teacher_timetable(Fetch::Next).await,
       Command::SetTeacher(ref name) => ctx.set_teacher(name).await,
+      Command::ToggleEditMode => ctx.toggle_edit_in_place().await,
+      Command::QuietHours(ref arg) if arg.trim().is_empty() => ctx.reply_quiet_hours().await,
+      Command::QuietHours(ref arg) => ctx.set_quiet_hours(arg).await,
+      Command::ForgetMe => ctx.reply_forget_me_agreement().await,
     };
 
     if let Err(ref err) = res {
@@ -94,19 +107,43 @@ pub enum DevCommand {
 
   #[command(description = "")]
   Broadcast(String),
+
+  #[command(description = "")]
+  DevExport,
+
+  #[command(description = "")]
+  DevImport(String),
+
+  #[command(description = "")]
+  DevHistory(String),
+
+  #[command(description = "")]
+  DevDeliveries(String),
+
+  #[command(description = "")]
+  DevStatus,
+
+  #[command(description = "")]
+  DevStats,
 }
 
 #[async_trait]
 impl Dispatch for DevCommand {
   type Kind = Message;
 
-  async fn dispatch(&self, bot: Bot, kind: Self::Kind, mongo: MongoPool) -> BotResult {
-    let ctx = Context::new(bot, kind, mongo);
+  async fn dispatch(&self, bot: Bot, kind: Self::Kind, db: Database) -> BotResult {
+    let ctx = Context::new(bot, kind, db);
 
     match self {
-      DevCommand::DevNotifiables => ctx.reply(format!("{:?}", ctx.mongo.notifiables().await?)).await?,
+      DevCommand::DevNotifiables => ctx.reply(format!("{:?}", ctx.db.notifiables().await?)).await?,
       DevCommand::DevUserList => ctx.dev_reply_user_list().await?,
       DevCommand::Broadcast(body) => ctx.dev_send_broadcast_agreement(body).await?,
+      DevCommand::DevExport => ctx.dev_send_export().await?,
+      DevCommand::DevImport(mode) => ctx.dev_import(mode).await?,
+      DevCommand::DevHistory(id) => ctx.dev_reply_history(id).await?,
+      DevCommand::DevDeliveries(id) => ctx.dev_reply_deliveries(id).await?,
+      DevCommand::DevStatus => ctx.dev_reply_status().await?,
+      DevCommand::DevStats => ctx.dev_reply_stats().await?,
     };
     Ok(())
   }
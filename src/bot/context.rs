@@ -6,12 +6,16 @@ use teloxide::{
   Bot,
 };
 
-use crate::{bot::BotResult, db::MongoPool, error::BotError};
+use crate::{
+  bot::{format::format_quiet_hours, BotResult},
+  db::{ChangeSource, Database, Settings},
+  error::BotError,
+};
 
 pub struct Context {
   bot: Bot,
   pub msg: Message,
-  pub mongo: MongoPool,
+  pub db: Database,
 }
 
 impl Deref for Context {
@@ -23,8 +27,8 @@ impl Deref for Context {
 }
 
 impl Context {
-  pub fn new(bot: Bot, msg: Message, mongo: MongoPool) -> Self {
-    Self { bot, msg, mongo }
+  pub fn new(bot: Bot, msg: Message, db: Database) -> Self {
+    Self { bot, msg, db }
   }
 
   pub fn chat_id(&self) -> ChatId {
@@ -49,27 +53,69 @@ impl Context {
       .disable_web_page_preview(true)
   }
 
+  /// Fails with [`BotError::UserNotFound`] until the user sends `/start`.
+  pub async fn user(&self) -> Result<Settings, BotError> {
+    self
+      .db
+      .get(self.chat_id())
+      .await?
+      .ok_or(BotError::UserNotFound(self.chat_id().0))
+  }
+
   pub async fn toggle_notifications(&self) -> BotResult {
-    let mut user = self.mongo.get_or_new(self.chat_id()).await?;
-    user.is_notifications_enabled = !user.is_notifications_enabled;
-    self.mongo.update(&user).await?;
-    self.reply(format!("{}", user.is_notifications_enabled)).await?;
+    let enabled = self
+      .db
+      .toggle_notifications(self.chat_id(), ChangeSource::Command)
+      .await?;
+    self.reply(format!("{}", enabled)).await?;
+    Ok(())
+  }
+
+  pub async fn toggle_edit_in_place(&self) -> BotResult {
+    let enabled = self
+      .db
+      .toggle_edit_in_place(self.chat_id(), ChangeSource::Command)
+      .await?;
+    let reply = match enabled {
+      true => "Изменения за тот же день будут обновлять прошлое уведомление",
+      false => "Каждое изменение будет приходить новым сообщением",
+    };
+    self.reply(reply).await?;
     Ok(())
   }
 
+  pub async fn set_quiet_hours(&self, arg: &str) -> BotResult {
+    let quiet_hours = match arg.trim() {
+      "off" => None,
+      x => Some(x.parse().map_err(|_| {
+        BotError::invalid_command("/quiet_hours", "/quiet_hours [ЧЧ:ММ-ЧЧ:ММ | off]", "/quiet_hours 22:30-07:00")
+      })?),
+    };
+
+    self
+      .db
+      .set_quiet_hours(self.chat_id(), quiet_hours, ChangeSource::Command)
+      .await?;
+    self.reply(format_quiet_hours(quiet_hours)).await
+  }
+
   pub async fn set_teacher(&self, name: &str) -> BotResult {
-    let mut user = self.mongo.get_or_new(self.chat_id()).await?;
     match name {
       "" => {
-        user.teacher = None;
+        self
+          .db
+          .set_teacher(self.chat_id(), None, ChangeSource::Command)
+          .await?;
         self.reply("Имя удалено").await?
       }
       x => {
-        user.teacher = Some(x.into());
+        self
+          .db
+          .set_teacher(self.chat_id(), Some(x), ChangeSource::Command)
+          .await?;
         self.reply(format!("Имя: {}", name)).await?;
       }
     };
-    self.mongo.update(&user).await?;
     Ok(())
   }
 }
@@ -0,0 +1,335 @@
+use std::{collections::HashMap, future::Future, sync::Mutex, time::Duration};
+
+use maiq_shared::utils::time::now;
+use mongodb::bson::DateTime;
+use teloxide::{
+  payloads::{EditMessageTextSetters, SendMessageSetters},
+  requests::Requester,
+  types::{ChatId, MessageId, ParseMode},
+  ApiError, Bot, RequestError,
+};
+use tokio::{
+  sync::mpsc::{self, UnboundedReceiver},
+  time::{sleep_until, Instant},
+};
+
+use crate::db::{DeliveryOutcome, DeliveryRecord, OutboxItem};
+
+/// Telegram allows ~30 messages per second overall, keep some headroom
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(40);
+
+/// And about one message per second to the same chat
+const PER_CHAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many times a message is rescheduled after `RetryAfter` before giving up
+const MAX_RETRY_AFTER: u32 = 5;
+
+/// Attempts for transient errors (network, Telegram 5xx), with exponential backoff between them
+const MAX_ATTEMPTS: u32 = 5;
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_CAP: Duration = Duration::from_secs(30);
+
+lazy_static! {
+  static ref LIMITS: Mutex<Limits> = Mutex::new(Limits::default());
+}
+
+#[derive(Debug, Clone)]
+pub struct Delivery {
+  /// Key of the outbox item this message was persisted as
+  pub key: String,
+  pub chat_id: ChatId,
+  pub text: String,
+  pub snapshot: Option<String>,
+  pub group: Option<String>,
+  pub target: Option<String>,
+  /// Message to edit in place, a new one is sent if the message can't be edited anymore
+  pub edit: Option<MessageId>,
+}
+
+#[derive(Debug)]
+pub struct Delivered {
+  pub delivery: Delivery,
+  /// Id of the sent or edited message
+  pub result: Result<MessageId, RequestError>,
+  /// Retries after transient errors, `RetryAfter` reschedules are not counted
+  pub retries: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DeliveryReport {
+  pub sent: usize,
+  /// Sent, but not on the first attempt
+  pub retried: usize,
+  pub failed: usize,
+  /// Failed with errors that will not go away until the user interacts with the bot again
+  pub permanently_failed: usize,
+}
+
+#[derive(Default)]
+struct Limits {
+  next_global: Option<Instant>,
+  last_per_chat: HashMap<ChatId, Instant>,
+  /// Set on `RetryAfter`, sends reserved before it have to wait for its end
+  paused_until: Option<Instant>,
+}
+
+impl From<OutboxItem> for Delivery {
+  fn from(item: OutboxItem) -> Self {
+    Self {
+      key: item.key,
+      chat_id: ChatId(item.chat_id),
+      text: item.text,
+      snapshot: item.snapshot,
+      group: item.group,
+      target: item.target,
+      edit: item.edit_message_id.map(MessageId),
+    }
+  }
+}
+
+impl Delivered {
+  pub fn is_permanent_failure(&self) -> bool {
+    matches!(self.result, Err(ref err) if is_permanent(err))
+  }
+
+  pub fn to_record(&self) -> DeliveryRecord {
+    let outcome = match self.result {
+      Ok(_) => DeliveryOutcome::Delivered,
+      Err(_) if self.is_permanent_failure() => DeliveryOutcome::Unreachable,
+      Err(_) => DeliveryOutcome::Failed,
+    };
+
+    DeliveryRecord {
+      chat_id: self.delivery.chat_id.0,
+      snapshot: self.delivery.snapshot.clone(),
+      group: self.delivery.group.clone(),
+      target: self.delivery.target.clone(),
+      message_id: self.result.as_ref().ok().map(|id| id.0),
+      timestamp: DateTime::from_chrono(now()),
+      outcome,
+      error: self.result.as_ref().err().map(ToString::to_string),
+    }
+  }
+}
+
+impl DeliveryReport {
+  pub fn add(&mut self, delivered: &Delivered) {
+    match delivered.result {
+      Ok(_) if delivered.retries > 0 => {
+        self.sent += 1;
+        self.retried += 1
+      }
+      Ok(_) => self.sent += 1,
+      Err(_) if delivered.is_permanent_failure() => self.permanently_failed += 1,
+      Err(_) => self.failed += 1,
+    }
+  }
+
+  pub fn total(&self) -> usize {
+    self.sent + self.failed + self.permanently_failed
+  }
+}
+
+impl std::fmt::Display for DeliveryReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "sent {} (retried {}), failed {}, permanently failed {} of {}",
+      self.sent,
+      self.retried,
+      self.failed,
+      self.permanently_failed,
+      self.total()
+    )
+  }
+}
+
+impl Limits {
+  /// Reserves the next send time for the chat respecting both global and per-chat limits.
+  fn reserve(&mut self, chat_id: ChatId) -> Instant {
+    let now = Instant::now();
+    let global = [self.next_global, self.paused_until]
+      .into_iter()
+      .flatten()
+      .fold(now, Instant::max);
+    let chat = self
+      .last_per_chat
+      .get(&chat_id)
+      .map_or(now, |last| *last + PER_CHAT_INTERVAL)
+      .max(now);
+
+    // Every message takes a global slot at the time it is actually sent, even if its chat holds it back
+    let at = global.max(chat);
+    self.next_global = Some(at + GLOBAL_INTERVAL);
+
+    if self.last_per_chat.len() > 1024 {
+      self.last_per_chat.retain(|_, last| *last + PER_CHAT_INTERVAL > now);
+    }
+    self.last_per_chat.insert(chat_id, at);
+    at
+  }
+
+  fn pause(&mut self, duration: Duration) {
+    let until = Instant::now() + duration;
+    self.paused_until = Some(self.paused_until.map_or(until, |paused| paused.max(until)));
+  }
+
+  fn is_paused(&self) -> bool {
+    matches!(self.paused_until, Some(until) if until > Instant::now())
+  }
+}
+
+/// Waits for a send slot of the chat. If a flood control pause started while waiting, the slot is reserved anew after it.
+async fn wait_slot(chat_id: ChatId) {
+  loop {
+    let at = LIMITS.lock().unwrap().reserve(chat_id);
+    sleep_until(at).await;
+    if !LIMITS.lock().unwrap().is_paused() {
+      return;
+    }
+  }
+}
+
+/// Sends all messages respecting Telegram rate limits, results are streamed as soon as each message is done.
+/// Messages rejected with `RetryAfter` are rescheduled, pausing all deliveries for the requested time.
+pub fn deliver(bot: &Bot, deliveries: Vec<Delivery>) -> UnboundedReceiver<Delivered> {
+  let (tx, rx) = mpsc::unbounded_channel();
+  for delivery in deliveries {
+    let (bot, tx) = (bot.clone(), tx.clone());
+    tokio::spawn(async move { tx.send(send(bot, delivery).await).ok() });
+  }
+
+  rx
+}
+
+async fn send(bot: Bot, delivery: Delivery) -> Delivered {
+  if let Some(message_id) = delivery.edit {
+    let (result, retries) = with_retries(&delivery, || edit(&bot, &delivery, message_id)).await;
+    match result {
+      Err(ref err) if is_uneditable(err) => {
+        warn!("Couldn't edit message #{} in #{}: {}, sending a new one", message_id.0, delivery.chat_id, err)
+      }
+      result => return Delivered { delivery, result, retries },
+    }
+  }
+
+  let (result, retries) = with_retries(&delivery, || send_new(&bot, &delivery)).await;
+  Delivered { delivery, result, retries }
+}
+
+/// Makes attempts in send slots, rescheduling after `RetryAfter` and backing off after transient errors.
+/// Returns the last result and the number of retries after transient errors.
+async fn with_retries<F, Fut>(delivery: &Delivery, mut attempt: F) -> (Result<MessageId, RequestError>, u32)
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<MessageId, RequestError>>,
+{
+  let mut retry_after = 0;
+  let mut retries = 0;
+  loop {
+    wait_slot(delivery.chat_id).await;
+
+    match attempt().await {
+      Err(RequestError::RetryAfter(after)) if retry_after < MAX_RETRY_AFTER => {
+        retry_after += 1;
+        warn!("Flood limit hit while sending to #{}, retrying after {}s", delivery.chat_id, after.as_secs());
+        LIMITS.lock().unwrap().pause(after);
+      }
+      Err(ref err) if is_transient(err) && retries + 1 < MAX_ATTEMPTS => {
+        let backoff = backoff(retries);
+        retries += 1;
+        warn!("Couldn't send to #{}: {}, retry #{} in {}ms", delivery.chat_id, err, retries, backoff.as_millis());
+        tokio::time::sleep(backoff).await;
+      }
+      result => return (result, retries),
+    }
+  }
+}
+
+async fn send_new(bot: &Bot, delivery: &Delivery) -> Result<MessageId, RequestError> {
+  bot
+    .send_message(delivery.chat_id, &delivery.text)
+    .parse_mode(ParseMode::Html)
+    .disable_web_page_preview(true)
+    .await
+    .map(|msg| msg.id)
+}
+
+async fn edit(bot: &Bot, delivery: &Delivery, message_id: MessageId) -> Result<MessageId, RequestError> {
+  let result = bot
+    .edit_message_text(delivery.chat_id, message_id, &delivery.text)
+    .parse_mode(ParseMode::Html)
+    .disable_web_page_preview(true)
+    .await;
+
+  match result {
+    Ok(msg) => Ok(msg.id),
+    Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(message_id),
+    Err(err) => Err(err),
+  }
+}
+
+fn backoff(retries: u32) -> Duration {
+  let exp = BACKOFF_BASE
+    .saturating_mul(2u32.saturating_pow(retries))
+    .min(BACKOFF_CAP);
+  exp + Duration::from_millis(fastrand::u64(0..=exp.as_millis() as u64 / 4))
+}
+
+/// Telegram 5xx reason phrases. Their status code is lost, so they come as unknown API errors like any unrecognized 4xx.
+const SERVER_ERRORS: [&str; 4] = ["Internal Server Error", "Bad Gateway", "Service Unavailable", "Gateway Timeout"];
+
+/// Network failures and Telegram-side errors are worth retrying, an html error page from the proxy comes as invalid json.
+fn is_transient(err: &RequestError) -> bool {
+  match err {
+    RequestError::Network(_) | RequestError::Io(_) | RequestError::InvalidJson { .. } => true,
+    RequestError::Api(ApiError::Unknown(description)) => SERVER_ERRORS.iter().any(|e| description.contains(e)),
+    _ => false,
+  }
+}
+
+/// The message is gone or too old, a new one has to be sent instead.
+fn is_uneditable(err: &RequestError) -> bool {
+  matches!(err, RequestError::Api(ApiError::MessageToEditNotFound | ApiError::MessageCantBeEdited | ApiError::MessageIdInvalid))
+}
+
+/// Errors after which there is no point in retrying until the user interacts with the bot again.
+pub fn is_permanent(err: &RequestError) -> bool {
+  matches!(
+    err,
+    RequestError::Api(
+      ApiError::BotBlocked
+        | ApiError::ChatNotFound
+        | ApiError::UserDeactivated
+        | ApiError::BotKicked
+        | ApiError::BotKickedFromSupergroup
+        | ApiError::GroupDeactivated
+        | ApiError::CantInitiateConversation
+    )
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reserve_takes_global_slot_when_held_back_by_chat() {
+    let mut limits = Limits::default();
+    let (a, b) = (ChatId(1), ChatId(2));
+    let first = limits.reserve(a);
+    let held = limits.reserve(a);
+    assert!(held >= first + PER_CHAT_INTERVAL);
+
+    let other = limits.reserve(b);
+    assert!(other >= held + GLOBAL_INTERVAL);
+  }
+
+  #[test]
+  fn reserve_waits_for_pause() {
+    let mut limits = Limits::default();
+    limits.pause(Duration::from_secs(5));
+    assert!(limits.is_paused());
+    assert!(limits.reserve(ChatId(1)) >= Instant::now() + Duration::from_secs(4));
+  }
+}
@@ -1,14 +1,16 @@
-use api::ApiError;
 use async_trait::async_trait;
 use chrono::{DateTime, Datelike, NaiveDate, Utc, Weekday};
-use maiq_api_wrapper as api;
 use maiq_shared::{
   default::{DefaultGroup, DefaultLesson},
   utils::time::now_date,
   Group, Lesson, Num, Snapshot,
 };
 
-use crate::error::{BotError, ReadableError};
+use crate::{
+  api::{self, ApiError},
+  error::{BotError, ReadableError},
+  quiet_hours::QuietHours,
+};
 
 pub trait SnapshotFormatter {
   fn format_group(&self, name: &str) -> Result<String, String>;
@@ -20,6 +22,11 @@ pub trait SnapshotFormatterExt {
   async fn format_or_default(&self, name: &str, date: NaiveDate) -> String;
 }
 
+pub trait DiffFormatter {
+  /// Renders what changed since the previous version of the group, `None` if lessons are the same.
+  fn format_diff(&self, previous: &Group) -> Option<String>;
+}
+
 pub trait DefaultFormatter {
   fn format(self, date: NaiveDate) -> String;
 }
@@ -71,6 +78,45 @@ impl SnapshotFormatterExt for Snapshot {
   }
 }
 
+impl DiffFormatter for Group {
+  fn format_diff(&self, previous: &Group) -> Option<String> {
+    let mut old: Vec<Option<&Lesson>> = previous.lessons.iter().map(Some).collect();
+    let mut take = |f: &dyn Fn(&Lesson) -> bool| {
+      old
+        .iter_mut()
+        .find(|o| matches!(o, Some(o) if f(o)))
+        .and_then(Option::take)
+    };
+
+    let mut changed = String::new();
+    let mut unmatched = vec![];
+    for lesson in self.lessons.iter() {
+      match take(&|o| o.name == lesson.name && lesson_slot(o) == lesson_slot(lesson)) {
+        Some(o) => changed.push_str(&format_lesson_changes(o, lesson)),
+        None => unmatched.push(lesson),
+      }
+    }
+
+    let mut added = String::new();
+    for lesson in unmatched {
+      match take(&|o| o.name == lesson.name) {
+        Some(o) => changed.push_str(&format_lesson_changes(o, lesson)),
+        None => added.push_str(&format!("➕ {}", format_lesson(lesson))),
+      }
+    }
+
+    let removed: String = old
+      .into_iter()
+      .flatten()
+      .map(|l| format!("➖ {}", format_lesson(l)))
+      .collect();
+    match [removed.as_str(), added.as_str(), changed.as_str()].concat() {
+      diff if diff.is_empty() => None,
+      diff => Some(format!("Что изменилось:\n{}", diff)),
+    }
+  }
+}
+
 impl DefaultFormatter for DefaultGroup {
   fn format(self, date: NaiveDate) -> String {
     let mut res = format!(
@@ -106,6 +152,13 @@ impl DefaultFormatter for Result<DefaultGroup, ApiError> {
   }
 }
 
+pub fn format_quiet_hours(quiet_hours: Option<QuietHours>) -> String {
+  match quiet_hours {
+    Some(q) => format!("Тихие часы: <b>{}</b>\nИзменения за это время придут после их окончания, только последняя версия", q),
+    None => "Тихие часы выключены, уведомления приходят сразу".into(),
+  }
+}
+
 pub trait NaiveDateExt {
   fn weekday_str_basic(&self) -> &str;
   fn weekday_str(&self) -> &str;
@@ -176,6 +229,44 @@ fn format_lesson(lesson: &Lesson) -> String {
   format!("{} <b>· {}</b>\n", res, lesson.name)
 }
 
+/// Lesson number and subgroup, e.g. `#2 · п/г 1`. Lessons without their own number share it with the previous one.
+fn lesson_slot(lesson: &Lesson) -> String {
+  let mut res = match lesson.num {
+    Num::Actual(ref num) => format!("#{}", num),
+    Num::Previous => "та же пара".into(),
+  };
+
+  if let Some(ref sub) = lesson.subgroup {
+    res.push_str(&format!(" · п/г {}", sub))
+  }
+  res
+}
+
+/// Empty if the lesson hasn't moved and both classroom and teacher are the same.
+fn format_lesson_changes(old: &Lesson, new: &Lesson) -> String {
+  let (old_slot, new_slot) = (lesson_slot(old), lesson_slot(new));
+  let moved = old_slot != new_slot;
+  let slot = match moved {
+    true => format!("🔀 <b>{} → {}</b>", old_slot, new_slot),
+    false => format!("✏️ <b>{}</b>", new_slot),
+  };
+
+  let mut changes = vec![];
+  if old.classroom != new.classroom {
+    changes.push(format!("кабинет {} → {}", old.classroom.as_deref().unwrap_or("-"), new.classroom.as_deref().unwrap_or("-")));
+  }
+
+  if old.teacher != new.teacher {
+    changes.push(format!("преподаватель {} → {}", old.teacher.as_deref().unwrap_or("-"), new.teacher.as_deref().unwrap_or("-")));
+  }
+
+  match (moved, changes.is_empty()) {
+    (false, true) => String::new(),
+    (true, true) => format!("{} · {}\n", slot, new.name),
+    (_, false) => format!("{} · {}: {}\n", slot, new.name, changes.join(", ")),
+  }
+}
+
 const EMOJIES: [&str; 21] =
   ["🥭", "🥩", "🥝", "🌵", "🥞", "🧀", "🍖", "🍌", "🍍", "🥓", "🧃", "🍒", "🍓", "🍇", "🥕", "🐷", "🍺", "🍪", "🍁", "🍉", "🍋"];
 
@@ -206,3 +297,80 @@ fn format_default_lesson(lesson: &DefaultLesson, is_even_week: bool) -> Option<S
   res.push('\n');
   Some(res)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lesson(num: Option<u8>, name: &str, classroom: &str, teacher: &str) -> Lesson {
+    Lesson {
+      num: num.map_or(Num::Previous, Num::Actual),
+      name: name.into(),
+      subgroup: None,
+      teacher: Some(teacher.into()),
+      classroom: Some(classroom.into()),
+    }
+  }
+
+  fn group(lessons: Vec<Lesson>) -> Group {
+    Group { name: "Ир3-21".into(), uid: "uid".into(), lessons }
+  }
+
+  fn diff(old: Vec<Lesson>, new: Vec<Lesson>) -> Option<String> {
+    group(new).format_diff(&group(old))
+  }
+
+  #[test]
+  fn no_change() {
+    let lessons = vec![lesson(Some(1), "Математика", "101", "Иванов"), lesson(Some(2), "Физика", "202", "Петров")];
+    assert_eq!(diff(lessons.clone(), lessons), None);
+  }
+
+  #[test]
+  fn removed() {
+    let diff = diff(
+      vec![lesson(Some(1), "Математика", "101", "Иванов"), lesson(Some(2), "Физика", "202", "Петров")],
+      vec![lesson(Some(1), "Математика", "101", "Иванов")],
+    );
+    assert_eq!(diff.unwrap(), "Что изменилось:\n➖ <b>#2</b> 202 <b>· Физика</b>\n");
+  }
+
+  #[test]
+  fn added() {
+    let diff = diff(
+      vec![lesson(Some(1), "Математика", "101", "Иванов")],
+      vec![lesson(Some(1), "Математика", "101", "Иванов"), lesson(Some(2), "Физика", "202", "Петров")],
+    );
+    assert_eq!(diff.unwrap(), "Что изменилось:\n➕ <b>#2</b> 202 <b>· Физика</b>\n");
+  }
+
+  #[test]
+  fn moved() {
+    let diff = diff(vec![lesson(Some(1), "Математика", "101", "Иванов")], vec![lesson(Some(3), "Математика", "101", "Иванов")]);
+    assert_eq!(diff.unwrap(), "Что изменилось:\n🔀 <b>#1 → #3</b> · Математика\n");
+  }
+
+  #[test]
+  fn classroom_and_teacher_changed() {
+    let diff = diff(vec![lesson(Some(2), "Физика", "202", "Петров")], vec![lesson(Some(2), "Физика", "303", "Сидоров")]);
+    assert_eq!(diff.unwrap(), "Что изменилось:\n✏️ <b>#2</b> · Физика: кабинет 202 → 303, преподаватель Петров → Сидоров\n");
+  }
+
+  #[test]
+  fn matches_by_slot_before_name() {
+    let diff = diff(
+      vec![lesson(Some(1), "Математика", "101", "Иванов"), lesson(Some(2), "Математика", "101", "Иванов")],
+      vec![lesson(Some(2), "Математика", "404", "Иванов")],
+    );
+    assert_eq!(
+      diff.unwrap(),
+      "Что изменилось:\n➖ <b>#1</b> 101 <b>· Математика</b>\n✏️ <b>#2</b> · Математика: кабинет 101 → 404\n"
+    );
+  }
+
+  #[test]
+  fn moved_from_previous_slot() {
+    let diff = diff(vec![lesson(None, "Физика", "202", "Петров")], vec![lesson(Some(1), "Физика", "202", "Петров")]);
+    assert_eq!(diff.unwrap(), "Что изменилось:\n🔀 <b>та же пара → #1</b> · Физика\n");
+  }
+}
@@ -1,12 +1,17 @@
 use async_trait::async_trait;
 
-use maiq_shared::utils::time::now_with_offset;
+use std::sync::RwLock;
+
+use chrono::{DateTime, Utc};
+use maiq_shared::utils::time::{now, now_with_offset};
 use teloxide::{
   dispatching::{HandlerExt, UpdateFilterExt, UpdateHandler},
   dptree as dp,
+  error_handlers::LoggingErrorHandler,
   prelude::Dispatcher,
   requests::Requester,
-  types::{CallbackQuery, Message, Update, UserId},
+  types::{CallbackQuery, Message, Update, UpdateKind, UserId},
+  update_listeners::webhooks,
   utils::command::BotCommands as _,
   Bot,
 };
@@ -16,9 +21,10 @@ use crate::{
     callbacks::CallbackKind,
     commands::{Command, DevCommand},
   },
-  db::MongoPool,
+  db::{ChangeSource, Database},
   env,
   error::BotError,
+  metrics::{self, CALLBACKS, COMMANDS},
 };
 
 pub mod notifier;
@@ -26,11 +32,14 @@ pub mod notifier;
 mod callbacks;
 mod commands;
 mod context;
+mod delivery;
 mod format;
 mod replies;
 
 lazy_static! {
   pub static ref DEV_ID: UserId = UserId(env::parse_var(env::DEV_ID).unwrap_or(0));
+  /// When the last update from Telegram was received
+  pub static ref LAST_UPDATE: RwLock<Option<DateTime<Utc>>> = RwLock::new(None);
 }
 
 pub type BotResult = Result<(), BotError>;
@@ -39,25 +48,52 @@ pub type BotResult = Result<(), BotError>;
 trait Dispatch {
   type Kind;
 
-  async fn dispatch(&self, bot: Bot, kind: Self::Kind, mongo: MongoPool) -> BotResult;
+  async fn dispatch(&self, bot: Bot, kind: Self::Kind, db: Database) -> BotResult;
 }
 
-pub async fn start(bot: Bot, pool: MongoPool) {
+pub async fn start(bot: Bot, db: Database) {
   bot
     .set_my_commands(Command::bot_commands())
     .await
     .expect("Couldn't set bot commands");
   let me = bot.get_me().await.expect("Login error");
-  bot.delete_webhook().await.expect("Couldn't delete webhook");
   info!("Logged in as {} [@{}]", me.full_name(), me.username());
-  info!("Started");
 
-  Dispatcher::builder(bot, dispatch_scheme())
-    .dependencies(dp::deps![pool])
+  let mut dispatcher = Dispatcher::builder(bot.clone(), dispatch_scheme())
+    .dependencies(dp::deps![db])
     .enable_ctrlc_handler()
-    .build()
-    .dispatch()
-    .await;
+    .build();
+
+  match webhook_options() {
+    Some(options) => {
+      info!("Started, receiving updates via webhook on {}", options.address);
+      let listener = webhooks::axum(bot, options).await.expect("Couldn't set webhook");
+      dispatcher
+        .dispatch_with_listener(listener, LoggingErrorHandler::with_custom_text("An error from the webhook listener"))
+        .await
+    }
+    None => {
+      bot.delete_webhook().await.expect("Couldn't delete webhook");
+      info!("Started, receiving updates via long polling");
+      dispatcher.dispatch().await
+    }
+  }
+}
+
+/// Webhook mode is enabled by `WEBHOOK_URL`, long polling is used otherwise.
+/// Teloxide generates a secret token if `WEBHOOK_SECRET` is not set.
+fn webhook_options() -> Option<webhooks::Options> {
+  let url = env::var(env::WEBHOOK_URL)?;
+  let url = url
+    .parse()
+    .unwrap_or_else(|_| panic!("Invalid webhook url `{}`", url));
+  let address = env::parse_var(env::WEBHOOK_LISTEN_ADDR).expect("Invalid webhook listen address");
+
+  let options = webhooks::Options::new(address, url);
+  match env::var(env::WEBHOOK_SECRET) {
+    Some(secret) => Some(options.secret_token(secret)),
+    None => Some(options),
+  }
 }
 
 fn dispatch_scheme() -> UpdateHandler<BotError> {
@@ -66,12 +102,14 @@ fn dispatch_scheme() -> UpdateHandler<BotError> {
     .branch(
       dp::entry()
         .filter_command::<Command>()
+        .inspect_async(count_command::<Command>)
         .endpoint(dispatch::<Command, Message>),
     )
     .branch(
       dp::entry()
         .filter_command::<DevCommand>()
         .filter(move |msg: Message| msg.from().unwrap().id == *DEV_ID)
+        .inspect_async(count_command::<DevCommand>)
         .endpoint(dispatch::<DevCommand, Message>),
     )
     .endpoint(unhandled_message);
@@ -79,12 +117,14 @@ fn dispatch_scheme() -> UpdateHandler<BotError> {
   let callback_handler = Update::filter_callback_query().endpoint(dispatch_query);
 
   dp::entry()
+    .inspect(|_: Update| *LAST_UPDATE.write().unwrap() = Some(now()))
+    .inspect_async(reactivate)
     .branch(cmds_handler)
     .branch(callback_handler)
     .endpoint(unhandled_update)
 }
 
-async fn dispatch_query(bot: Bot, query: CallbackQuery, mongo: MongoPool) -> BotResult {
+async fn dispatch_query(bot: Bot, query: CallbackQuery, db: Database) -> BotResult {
   let kind: CallbackKind = query
     .data
     .as_ref()
@@ -92,7 +132,34 @@ async fn dispatch_query(bot: Bot, query: CallbackQuery, mongo: MongoPool) -> Bot
     .unwrap_or(CallbackKind::Unknown);
 
   info!("Callback {:?} from {}", kind, query.from.full_name());
-  dispatch(kind, bot, query, mongo).await
+  CALLBACKS.inc(&[&metrics::variant(&kind)]);
+  dispatch(kind, bot, query, db).await
+}
+
+async fn count_command<T: std::fmt::Debug>(command: T, db: Database) {
+  let name = metrics::variant(&command);
+  COMMANDS.inc(&[&name]);
+  if let Err(err) = db.count_command(&name).await {
+    error!("Couldn't count command {}: {}", name, err);
+  }
+}
+
+/// Any interaction proves the chat is reachable again after failed deliveries, it is also recorded for usage stats.
+/// Blocking the bot is not an interaction.
+async fn reactivate(update: Update, db: Database) {
+  let source = match update.kind {
+    UpdateKind::CallbackQuery(_) => ChangeSource::Callback,
+    UpdateKind::MyChatMember(ref member) if member.new_chat_member.kind.is_banned() => return,
+    _ => ChangeSource::Command,
+  };
+
+  if let Some(chat) = update.chat() {
+    match db.touch(chat.id, source).await {
+      Ok(true) => info!("Chat #{} is reachable again", chat.id),
+      Ok(false) => (),
+      Err(err) => error!("Couldn't record interaction of chat #{}: {}", chat.id, err),
+    }
+  }
 }
 
 async fn unhandled_message(msg: Message) -> BotResult {
@@ -123,7 +190,7 @@ async fn unhandled_update(update: Update) -> BotResult {
   Ok(())
 }
 
-async fn dispatch<T: Dispatch<Kind = K>, K>(dispatchable: T, bot: Bot, kind: K, db: MongoPool) -> BotResult {
+async fn dispatch<T: Dispatch<Kind = K>, K>(dispatchable: T, bot: Bot, kind: K, db: Database) -> BotResult {
   if let Err(ref err) = dispatchable.dispatch(bot, kind, db).await {
     error!("{err}");
   }
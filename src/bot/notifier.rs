@@ -1,19 +1,85 @@
-use std::{future::IntoFuture, time::Duration};
+use std::collections::{HashMap, HashSet};
 
-use maiq_shared::Snapshot;
+use chrono::Duration;
+use maiq_shared::{utils::time::now, Fetch, Group, Snapshot};
+use mongodb::bson::DateTime;
 use teloxide::{
   payloads::SendMessageSetters,
   requests::Requester,
   types::{ChatId, ParseMode},
   Bot,
 };
-use tokio::task::JoinSet;
+use tokio::time::{sleep, Instant};
 
-use crate::{bot::format::SnapshotFormatterExt, db::MongoPool, error::BotError};
+use crate::{
+  bot::{
+    delivery::{deliver, is_permanent, Delivery, DeliveryReport},
+    format::{DiffFormatter, SnapshotFormatterExt},
+    DEV_ID,
+  },
+  db::{ChangeSource, Database, OutboxItem, OutboxStatus, SeenGroup},
+  env,
+  error::BotError,
+  metrics::NOTIFICATIONS,
+  quiet_hours::local_now,
+};
+
+/// Finished outbox items are kept for a while for debugging
+const OUTBOX_TTL_DAYS: i64 = 7;
+
+/// Undelivered messages older than this are not resent after a restart, the timetable in them is likely outdated
+const PENDING_TTL_HOURS: i64 = 12;
+
+const DEFERRED_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
 
-pub async fn notify_update(bot: &Bot, mongo: &MongoPool, snapshot: Snapshot, changes: Vec<String>) -> Result<(), BotError> {
+/// Long enough to answer "did I get the notification last week?"
+const DELIVERY_LOG_TTL_DAYS: i64 = 30;
+
+pub async fn notify_update(
+  bot: &Bot,
+  db: &Database,
+  snapshot: Snapshot,
+  fetch: Fetch,
+  mut changes: Vec<String>,
+) -> Result<(), BotError> {
   info!("Changed groups: {:?}", changes);
-  let notifiables = mongo.notifiables().await?;
+  let date = snapshot.date.date_naive().to_string();
+  let target = format!("{}:{:?}", date, fetch);
+  let seen: HashMap<String, SeenGroup> = db
+    .seen_groups(&date)
+    .await?
+    .into_iter()
+    .map(|s| (s.group.clone(), s))
+    .collect();
+
+  let mut fresh = vec![];
+  changes.retain(|name| {
+    let content = match snapshot.group(name).and_then(|g| serde_json::to_string(g).ok()) {
+      Some(content) => content,
+      None => return true,
+    };
+
+    if matches!(seen.get(name), Some(s) if s.content == content) {
+      info!("Group {} is the same as already notified [{}], skipping", name, snapshot.uid);
+      return false;
+    }
+
+    let updated = DateTime::from_chrono(now());
+    fresh.push(SeenGroup { date: date.clone(), group: name.clone(), snapshot: snapshot.uid.clone(), content, updated });
+    true
+  });
+
+  if changes.is_empty() {
+    return Ok(());
+  }
+
+  let notifiables = db.notifiables().await?;
+  let edit_in_place: HashSet<i64> = db.fetch_edit_in_place_ids().await?.into_iter().collect();
+  let quiet_hours = db.fetch_quiet_hours().await?;
+  let local_time = local_now().time();
+  let mut deliveries = vec![];
   for notifiable in notifiables {
     if !changes.contains(&notifiable.group) {
       continue;
@@ -22,39 +88,261 @@ pub async fn notify_update(bot: &Bot, mongo: &MongoPool, snapshot: Snapshot, cha
     let body = snapshot
       .format_or_default(&notifiable.group, snapshot.date.date_naive())
       .await;
+    let previous = seen
+      .get(&notifiable.group)
+      .and_then(|s| serde_json::from_str::<Group>(&s.content).ok());
+    let body = match (snapshot.group(&notifiable.group), previous) {
+      (Some(group), Some(previous)) => with_diff(group, &previous, body),
+      _ => body,
+    };
 
-    send_to_all(bot, &body, notifiable.ids.as_slice()).await;
+    let last_messages = match notifiable.ids.iter().any(|id| edit_in_place.contains(id)) {
+      true => db.last_messages(&target, &notifiable.group).await?,
+      false => HashMap::new(),
+    };
+    let updated = format!("{}\n\n<i>Обновлено в {}</i>", body, now().format("%H:%M"));
+
+    info!("Notifying group {} ({} users)", notifiable.group, notifiable.ids.len());
+    for &id in notifiable.ids.iter() {
+      let item = match last_messages.get(&id).filter(|_| edit_in_place.contains(&id)) {
+        Some(&message_id) => OutboxItem::new(ChatId(id), updated.as_str()).editing(message_id),
+        None => OutboxItem::new(ChatId(id), body.as_str()),
+      };
+      let item = match quiet_hours.get(&id).and_then(|q| q.remaining(local_time)) {
+        Some(left) => item.deferred(DateTime::from_chrono(now() + left)),
+        None => item,
+      };
+      deliveries.push(item.with_origin(&snapshot.uid, &notifiable.group, &target));
+    }
   }
+
+  let report = send_all(bot, db, deliveries, &fresh).await;
+  report_to_dev(bot, &format!("Уведомление [<code>{}</code>] для {:?}", snapshot.uid, changes), &report).await;
   Ok(())
 }
 
-pub async fn send_to_all(bot: &Bot, msg: &str, ids: &[i64]) {
-  let mut handles = JoinSet::new();
-
-  for &id in ids {
-    handles.spawn(
-      bot
-        .send_message(ChatId(id), msg)
-        .parse_mode(ParseMode::Html)
-        .disable_web_page_preview(true)
-        .into_future(),
-    );
+/// Appends the diff against the previously notified version of the group, if anything has changed in lessons.
+fn with_diff(group: &Group, previous: &Group, body: String) -> String {
+  match group.format_diff(previous) {
+    Some(diff) => format!("{}\n{}", body, diff),
+    None => body,
   }
+}
 
+pub async fn send_to_all(bot: &Bot, db: &Database, msg: &str, ids: &[i64]) {
   info!("Sending message to users {:?} ({})..", ids, ids.len());
-  for (idx, handle) in handles.join_next().await.iter().enumerate() {
-    if let Err(err) = handle {
-      warn!("Error occured while notifying users at [{}]: {}", idx, err);
+  send_all(bot, db, ids.iter().map(|&id| OutboxItem::new(ChatId(id), msg)).collect(), &[]).await;
+}
+
+/// Drops finished outbox items, old delivery log entries and seen groups for past dates.
+pub async fn cleanup(db: &Database) -> Result<(), BotError> {
+  let purged = db
+    .purge_outbox(DateTime::from_chrono(now() - Duration::days(OUTBOX_TTL_DAYS)))
+    .await?;
+  if purged > 0 {
+    info!("Purged {} finished outbox items", purged);
+  }
+
+  let purged = db
+    .purge_deliveries(DateTime::from_chrono(now() - Duration::days(DELIVERY_LOG_TTL_DAYS)))
+    .await?;
+  if purged > 0 {
+    info!("Purged {} delivery log entries", purged);
+  }
+
+  let purged = db.purge_seen(&now().date_naive().to_string()).await?;
+  if purged > 0 {
+    info!("Purged {} seen groups", purged);
+  }
+  Ok(())
+}
+
+/// Sends undelivered messages left after the previous run, including deferred ones that are due.
+pub async fn resume_outbox(bot: &Bot, db: &Database) -> Result<(), BotError> {
+  let mut pending = db.pending_outbox().await?;
+  let deferred = db.deferred_outbox(DateTime::from_chrono(now())).await?;
+  pending.extend(drop_forgotten(db, deferred).await);
+
+  let stale_before = DateTime::from_chrono(now() - Duration::hours(PENDING_TTL_HOURS));
+  let (stale, pending): (Vec<_>, Vec<_>) = pending
+    .into_iter()
+    .partition(|i| i.not_before.unwrap_or(i.created) < stale_before);
+  if !stale.is_empty() {
+    warn!("Dropping {} undelivered messages older than {}h", stale.len(), PENDING_TTL_HOURS);
+  }
+  for item in stale {
+    if let Err(err) = db.finish_outbox(&item.key, OutboxStatus::Failed).await {
+      error!("Couldn't update outbox item {}: {}", item.key, err);
+    }
+  }
+
+  if pending.is_empty() {
+    return Ok(());
+  }
+
+  info!("Resuming {} undelivered messages", pending.len());
+  let report = deliver_outbox(bot, db, pending).await;
+  report_to_dev(bot, "Досылка после перезапуска", &report).await;
+  Ok(())
+}
+
+/// Sends messages deferred due to users' quiet hours once they are due and runs [`cleanup`] daily.
+/// Must be started after [`resume_outbox`].
+pub async fn run_deferred(bot: Bot, db: Database) {
+  let mut last_cleanup = Instant::now();
+  loop {
+    sleep(DEFERRED_CHECK_INTERVAL).await;
+    if last_cleanup.elapsed() >= CLEANUP_INTERVAL {
+      if let Err(err) = cleanup(&db).await {
+        error!("Couldn't clean up the storage: {}", err);
+      }
+      last_cleanup = Instant::now();
     }
+    let items = match db.deferred_outbox(DateTime::from_chrono(now())).await {
+      Ok(items) => drop_forgotten(&db, items).await,
+      Err(err) => {
+        error!("Couldn't fetch deferred messages: {}", err);
+        continue;
+      }
+    };
 
-    if let Err(req_err) = handle.as_ref().unwrap() {
-      warn!("Request error occured while notifying users at [{}]: {}", idx, req_err);
+    match items.is_empty() {
+      true => (),
+      false => {
+        info!("Sending {} deferred messages", items.len());
+        deliver_outbox(&bot, &db, items).await;
+      }
+    }
+  }
+}
+
+/// Supersedes deferred messages to users deleted while they were waiting for the end of quiet hours.
+async fn drop_forgotten(db: &Database, items: Vec<OutboxItem>) -> Vec<OutboxItem> {
+  let mut known = HashMap::new();
+  let mut kept = vec![];
+  for item in items {
+    let exists = match known.get(&item.chat_id) {
+      Some(&exists) => exists,
+      None => {
+        let exists = !matches!(db.get(ChatId(item.chat_id)).await, Ok(None));
+        known.insert(item.chat_id, exists);
+        exists
+      }
+    };
+
+    match exists {
+      true => kept.push(item),
+      false => {
+        info!("User-id {} is gone, dropping deferred message {}", item.chat_id, item.key);
+        if let Err(err) = db.finish_outbox(&item.key, OutboxStatus::Superseded).await {
+          error!("Couldn't update outbox item {}: {}", item.key, err);
+        }
+      }
+    }
+  }
+  kept
+}
+
+/// Deferred messages are only persisted, the rest are sent right away.
+/// Older deferred messages about the same target are superseded by any newer one, so only the latest version is sent.
+/// Groups are saved as seen once the messages about them are persisted, so a failure before that doesn't lose the notification.
+async fn send_all(bot: &Bot, db: &Database, items: Vec<OutboxItem>, seen: &[SeenGroup]) -> DeliveryReport {
+  let mut by_target: HashMap<&str, Vec<ChatId>> = HashMap::new();
+  for item in items.iter() {
+    if let Some(ref target) = item.target {
+      by_target.entry(target).or_default().push(ChatId(item.chat_id));
+    }
+  }
+
+  for (target, chat_ids) in by_target {
+    if let Err(err) = db.supersede_outbox(&chat_ids, target).await {
+      error!("Couldn't supersede deferred messages about {} to {} users: {}", target, chat_ids.len(), err);
+    }
+  }
+
+  match db.push_outbox(&items).await {
+    Ok(()) if !seen.is_empty() => {
+      if let Err(err) = db.save_seen(seen).await {
+        error!("Couldn't save seen groups: {}", err);
+      }
+    }
+    Ok(()) => (),
+    Err(err) => error!("Couldn't persist {} messages to outbox, sending anyway: {}", items.len(), err),
+  }
+
+  let (deferred, items): (Vec<_>, Vec<_>) = items.into_iter().partition(|i| i.not_before.is_some());
+  if !deferred.is_empty() {
+    info!("Deferred {} messages due to users' quiet hours", deferred.len());
+  }
+
+  deliver_outbox(bot, db, items).await
+}
+
+async fn deliver_outbox(bot: &Bot, db: &Database, items: Vec<OutboxItem>) -> DeliveryReport {
+  let mut report = DeliveryReport::default();
+  let mut results = deliver(bot, items.into_iter().map(Delivery::from).collect());
+  while let Some(delivered) = results.recv().await {
+    report.add(&delivered);
+    let status = match delivered.result {
+      Ok(_) => OutboxStatus::Delivered,
+      Err(_) => OutboxStatus::Failed,
+    };
+
+    if let Err(err) = db.finish_outbox(&delivered.delivery.key, status).await {
+      error!("Couldn't update outbox item {}: {}", delivered.delivery.key, err);
+    }
+
+    let record = delivered.to_record();
+    let group = record.group.as_deref().unwrap_or("none");
+    NOTIFICATIONS.inc(&[group, record.outcome.as_str()]);
+    if let Err(err) = db.log_delivery(&record).await {
+      error!("Couldn't log delivery to user-id {}: {}", delivered.delivery.chat_id, err);
+    }
+
+    let id = delivered.delivery.chat_id;
+    let req_err = match delivered.result {
+      Ok(_) => continue,
+      Err(ref err) => err,
+    };
+
+    if !is_permanent(req_err) {
+      warn!("Request error occured while notifying user-id {}: {}", id, req_err);
+      continue;
     }
 
-    if idx % 25 == 0 {
-      tokio::time::sleep(Duration::from_secs(1)).await
+    warn!("User-id {} is unreachable: {}", id, req_err);
+    if let Err(err) = db.set_unreachable(id, true, ChangeSource::Delivery).await {
+      error!("Couldn't mark user-id {} as unreachable: {}", id, err);
     }
   }
 
-  info!("Sending done");
+  info!("Sending done: {}", report);
+  report
+}
+
+async fn report_to_dev(bot: &Bot, title: &str, report: &DeliveryReport) {
+  if !env::parse_var(env::DEV_DELIVERY_REPORTS).unwrap_or(false) {
+    return;
+  }
+
+  let body = format!(
+    "{}\n\nОтправлено: <b>{}</b> (с повтором: {})\nОшибки: <b>{}</b>\nНедоступны: <b>{}</b>",
+    title, report.sent, report.retried, report.failed, report.permanently_failed
+  );
+  send_to_dev(bot, body).await
+}
+
+/// Does nothing if `DEV_ID` is not set.
+pub async fn send_to_dev<T: Into<String>>(bot: &Bot, text: T) {
+  if DEV_ID.0 == 0 {
+    return;
+  }
+
+  if let Err(err) = bot
+    .send_message(ChatId::from(*DEV_ID), text)
+    .parse_mode(ParseMode::Html)
+    .await
+  {
+    error!("Couldn't send a message to dev: {}", err);
+  }
 }
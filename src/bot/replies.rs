@@ -1,16 +1,23 @@
-use chrono::{Datelike, NaiveDate};
-use maiq_api_wrapper as api;
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc};
 use maiq_shared::{utils::time::now, Fetch};
+use mongodb::bson::DateTime as BsonDateTime;
 use teloxide::{
-  payloads::SendMessageSetters,
+  net::Download,
+  payloads::{SendDocumentSetters, SendMessageSetters},
   requests::Requester,
-  types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode},
+  types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, ParseMode},
 };
 
 use crate::{
-  bot::format::{SnapshotFormatter, SnapshotFormatterExt},
-  db::Settings,
+  api,
+  backup::{self, Backup},
+  bot::format::{format_quiet_hours, SnapshotFormatter, SnapshotFormatterExt},
+  db::{Change, DeliveryRecord, Settings},
   error::BotError,
+  poller::STATUS,
+  quiet_hours::QuietHours,
 };
 
 use super::{
@@ -20,6 +27,9 @@ use super::{
   get_next_day, BotResult,
 };
 
+/// Lines per section of `/dev_stats`, so it fits into a single message
+const STATS_TOP: usize = 30;
+
 macro_rules! url_buttons {
   ($(($(($name: literal, $url: literal)),*)),*) => {
     InlineKeyboardMarkup::new(vec![$(vec![$(InlineKeyboardButton::url($name, reqwest::Url::parse($url).unwrap())),*]),*])
@@ -28,7 +38,7 @@ macro_rules! url_buttons {
 
 impl Context {
   pub async fn start(&self) -> BotResult {
-    self.mongo.get_or_new(self.chat_id()).await?;
+    self.db.get_or_new(self.chat_id()).await?;
     let username = &self.msg.from().unwrap().first_name;
     self
       .reply(format!(
@@ -62,6 +72,12 @@ impl Context {
 
   · Можно отключить/включить уведомления при помощи /toggle_notifications.
 
+  · Если расписание меняется несколько раз за день, /toggle_edit_mode включит обновление прошлого уведомления вместо отправки нового
+
+  · Тихие часы, во время которых уведомления не приходят, настраиваются командой /quiet_hours
+
+  · Удалить все свои данные из бота можно командой /forget_me
+
   · Бота можно добавить в чат, команды работать будут, но уведомления - нет
 
   · Если вместо названия пар написано <b>По расписанию</b>, значит нужно заполнить стандартное расписание, спрашивай <a href="https://t.me/pashokitsme">тут</a>.
@@ -108,8 +124,43 @@ impl Context {
     Ok(())
   }
 
+  pub async fn reply_quiet_hours(&self) -> BotResult {
+    let user = self.user().await?;
+    let preset = |start: u32, end: u32| {
+      let q = QuietHours::new(NaiveTime::from_hms_opt(start, 0, 0).unwrap(), NaiveTime::from_hms_opt(end, 0, 0).unwrap());
+      Callback::button(q.to_string(), CallbackKind::SetQuietHours(Some(q)))
+    };
+
+    let buttons = vec![
+      vec![preset(22, 7), preset(23, 7)],
+      vec![preset(23, 8), preset(0, 9)],
+      vec![Callback::button("Выключить", CallbackKind::SetQuietHours(None))],
+    ];
+
+    let body = format!("{}\n\nВыбери ниже или задай свои: /quiet_hours 22:30-07:00", format_quiet_hours(user.quiet_hours));
+    self
+      .reply_ex(body)
+      .reply_markup(InlineKeyboardMarkup::new(buttons))
+      .await?;
+    Ok(())
+  }
+
+  pub async fn reply_forget_me_agreement(&self) -> BotResult {
+    let requester = self
+      .msg
+      .from()
+      .map_or(self.msg.chat.id.0.to_string(), |u| u.id.0.to_string());
+    let buttons =
+      vec![vec![Callback::button("Удалить", CallbackKind::ForgetMe(requester)), Callback::button("Отмена", CallbackKind::Del)]];
+    self
+      .reply_ex("Удалить все твои данные? Уведомления перестанут приходить, а группу и имя придётся указать заново")
+      .reply_markup(InlineKeyboardMarkup::new(buttons))
+      .await?;
+    Ok(())
+  }
+
   pub async fn reply_timetable(&self, fetch: Fetch) -> BotResult {
-    let group = self.mongo.get_or_new(self.chat_id()).await?.group;
+    let group = self.user().await?.group;
 
     let group = match group {
       Some(g) => g,
@@ -128,7 +179,7 @@ impl Context {
   }
 
   pub async fn reply_default(&self, date: NaiveDate) -> BotResult {
-    match self.mongo.get_or_new(self.chat_id()).await?.group {
+    match self.user().await?.group {
       Some(g) => self.reply(api::default(&g, date.weekday()).await.format(date)).await,
       None => self.reply("Ты не указал группу").await.map(|_| ()),
     }
@@ -146,7 +197,7 @@ impl Context {
       NaiveDate::from_ymd_opt(y, m, d).ok_or(())
     }
 
-    let group = match self.mongo.get_or_new(self.chat_id()).await?.group {
+    let group = match self.user().await?.group {
       Some(g) => g,
       None => return self.reply("Группа не указана").await.map(|_| ()),
     };
@@ -162,7 +213,7 @@ impl Context {
   }
 
   pub async fn reply_teacher_timetable(&self, fetch: Fetch) -> BotResult {
-    let name = self.mongo.get_or_new(self.chat_id()).await?.teacher;
+    let name = self.user().await?.teacher;
     if name.is_none() {
       return self.reply("Имя не указано").await;
     }
@@ -171,11 +222,12 @@ impl Context {
   }
 
   pub async fn dev_reply_user_list(&self) -> BotResult {
-    let users = self.mongo.fetch_all().await?;
+    let users = self.db.fetch_all().await?;
     let format = |u: &Settings| -> String {
-      let r = match u.is_notifications_enabled {
-        true => "[+] ",
-        false => "[-] ",
+      let r = match (u.is_notifications_enabled, u.is_unreachable) {
+        (_, true) => "[x] ",
+        (true, false) => "[+] ",
+        (false, false) => "[-] ",
       };
 
       format!(
@@ -222,4 +274,179 @@ impl Context {
 
     Ok(())
   }
+
+  pub async fn dev_send_export(&self) -> BotResult {
+    let backup = backup::export(&self.db).await?;
+    let name = format!("users-{}.json", backup.created.format("%Y-%m-%d-%H%M%S"));
+    self
+      .send_document(self.chat_id(), InputFile::memory(backup.to_json()?).file_name(name))
+      .caption(format!("Всего: <b>{}</b>", backup.users.len()))
+      .parse_mode(ParseMode::Html)
+      .await?;
+    Ok(())
+  }
+
+  /// Has to be a reply to the backup file. `/dev_import dry` only reports conflicting ids.
+  pub async fn dev_import(&self, mode: &str) -> BotResult {
+    let document = match self.msg.reply_to_message().and_then(|m| m.document()) {
+      Some(d) => d,
+      None => return self.reply("Нужно ответить командой на файл бэкапа").await,
+    };
+
+    let file = self.get_file(&document.file.id).await?;
+    let mut raw = vec![];
+    self.download_file(&file.path, &mut raw).await?;
+
+    let backup = Backup::from_json(&raw)?;
+    let report = backup::import(&self.db, backup, mode.trim() == "dry").await?;
+    self.reply(report.to_string()).await
+  }
+
+  pub async fn dev_reply_history(&self, id: &str) -> BotResult {
+    let id = id
+      .trim()
+      .parse()
+      .map_err(|_| BotError::invalid_command("/dev_history", "/dev_history [user-id]", "/dev_history 123456789"))?;
+    let history = self.db.history(ChatId(id), 50).await?;
+    if history.is_empty() {
+      return self.reply(format!("Нет изменений для <code>{}</code>", id)).await;
+    }
+
+    let format = |c: &Change| -> String {
+      format!(
+        "{} [{}] <b>{}</b>: {} → {}\n",
+        c.timestamp.to_chrono().format("%d/%m/%Y %H:%M:%S"),
+        c.source.as_str(),
+        c.field,
+        c.old.as_deref().unwrap_or("-"),
+        c.new.as_deref().unwrap_or("-")
+      )
+    };
+
+    let body = format!("История <code>{}</code>:\n\n{}", id, history.iter().map(format).collect::<String>());
+    self.reply(body).await
+  }
+
+  pub async fn dev_reply_deliveries(&self, id: &str) -> BotResult {
+    let id = id
+      .trim()
+      .parse()
+      .map_err(|_| BotError::invalid_command("/dev_deliveries", "/dev_deliveries [user-id]", "/dev_deliveries 123456789"))?;
+    let deliveries = self.db.deliveries(ChatId(id), 30).await?;
+    if deliveries.is_empty() {
+      return self
+        .reply(format!("Нет отправленных уведомлений для <code>{}</code>", id))
+        .await;
+    }
+
+    let format = |d: &DeliveryRecord| -> String {
+      let mut line = format!(
+        "{} [{}] <b>{}</b> <code>{}</code>",
+        d.timestamp.to_chrono().format("%d/%m/%Y %H:%M:%S"),
+        d.outcome.as_str(),
+        d.group.as_deref().unwrap_or("рассылка"),
+        d.snapshot.as_deref().unwrap_or("-"),
+      );
+      if let Some(message_id) = d.message_id {
+        line.push_str(&format!(" #{}", message_id));
+      }
+      if let Some(ref err) = d.error {
+        line.push_str(&format!(": {}", err));
+      }
+      line.push('\n');
+      line
+    };
+
+    let last = match self.db.last_notified(ChatId(id)).await? {
+      Some(ref d) => format(d),
+      None => "-\n".into(),
+    };
+
+    let body = format!(
+      "Последнее уведомление <code>{}</code>:\n{}\nДоставки:\n\n{}",
+      id,
+      last,
+      deliveries.iter().map(format).collect::<String>()
+    );
+    self.reply(body).await
+  }
+
+  pub async fn dev_reply_status(&self) -> BotResult {
+    let status = STATUS.read().unwrap().clone();
+    let time = |t: Option<DateTime<Utc>>| t.map_or("-".into(), |t| t.format("%d/%m/%Y %H:%M:%S").to_string());
+    let body = format!(
+      "Api: <b>{}</b>\nПоследний опрос: {}\nОшибок подряд: {}\nНедоступно с: {}\nСледующая попытка: {}\nПоследняя ошибка: <code>{}</code>",
+      status.breaker,
+      time(status.last_poll),
+      status.failures,
+      time(status.down_since),
+      time(status.retry_at),
+      status.last_error.as_deref().unwrap_or("-")
+    );
+    self.reply(body).await
+  }
+
+  pub async fn dev_reply_stats(&self) -> BotResult {
+    let users = self.db.fetch_all().await?;
+    let usage = self.db.command_usage().await?;
+    let active = |days: i64| {
+      let since = BsonDateTime::from_chrono(now() - Duration::days(days));
+      users
+        .iter()
+        .filter(|u| matches!(u.last_seen, Some(at) if at >= since))
+        .count()
+    };
+    let percent = |n: usize| match users.len() {
+      0 => 0.0,
+      total => n as f32 * 100.0 / total as f32,
+    };
+
+    let notified = users.iter().filter(|u| u.is_notifications_enabled).count();
+    let mut body = format!(
+      "Всего: <b>{}</b>\nАктивны за 7 дней: {}\nАктивны за 30 дней: {}\nС уведомлениями: {} ({:.1}%)\nНедоступны: {}\nПреподаватели: {}\n",
+      users.len(),
+      active(7),
+      active(30),
+      notified,
+      percent(notified),
+      users.iter().filter(|u| u.is_unreachable).count(),
+      users.iter().filter(|u| u.teacher.is_some()).count(),
+    );
+
+    let mut groups: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for user in users.iter() {
+      let group = groups.entry(user.group.as_deref().unwrap_or("-")).or_default();
+      group.0 += 1;
+      group.1 += user.is_notifications_enabled as usize;
+    }
+
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    groups.sort_by_key(|(_, (total, _))| std::cmp::Reverse(*total));
+    body.push_str("\n<b>Группы</b> (всего/с уведомлениями):\n");
+    push_top(
+      &mut body,
+      groups
+        .iter()
+        .map(|(group, (total, notified))| format!("{}: {}/{}", group, total, notified)),
+    );
+
+    let mut usage: Vec<(String, u64)> = usage.into_iter().collect();
+    usage.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    body.push_str("\n<b>Команды</b>:\n");
+    push_top(&mut body, usage.iter().map(|(command, count)| format!("{}: {}", command, count)));
+
+    self.reply(body).await
+  }
+}
+
+/// Appends the first [`STATS_TOP`] lines and how many are left out.
+fn push_top(body: &mut String, lines: impl ExactSizeIterator<Item = String>) {
+  let rest = lines.len().saturating_sub(STATS_TOP);
+  for line in lines.take(STATS_TOP) {
+    body.push_str(&line);
+    body.push('\n');
+  }
+  if rest > 0 {
+    body.push_str(&format!("…и ещё {}\n", rest));
+  }
 }
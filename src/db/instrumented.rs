@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use mongodb::bson::DateTime;
+use teloxide::types::ChatId;
+
+use crate::{
+  db::{
+    Change, ChangeSource, Database, DeliveryLogStore, DeliveryRecord, Notifiable, OutboxItem, OutboxStatus, OutboxStore,
+    SeenGroup, Settings, SettingsStore, SnapshotStore, StatsStore, Storage,
+  },
+  error::BotError,
+  metrics::STORAGE_LATENCY,
+  quiet_hours::QuietHours,
+};
+
+/// Records latency of every operation of the wrapped storage.
+pub struct Instrumented(pub Database);
+
+#[async_trait]
+impl Storage for Instrumented {
+  async fn ping(&self) -> Result<(), BotError> {
+    STORAGE_LATENCY.time(&["ping"], self.0.ping()).await
+  }
+}
+
+#[async_trait]
+impl SettingsStore for Instrumented {
+  async fn get(&self, id: ChatId) -> Result<Option<Settings>, BotError> {
+    STORAGE_LATENCY.time(&["get"], self.0.get(id)).await
+  }
+
+  async fn get_or_new(&self, id: ChatId) -> Result<Settings, BotError> {
+    STORAGE_LATENCY.time(&["get_or_new"], self.0.get_or_new(id)).await
+  }
+
+  async fn delete(&self, id: ChatId) -> Result<bool, BotError> {
+    STORAGE_LATENCY.time(&["delete"], self.0.delete(id)).await
+  }
+
+  async fn select_group(&self, id: ChatId, group: &str, source: ChangeSource) -> Result<(), BotError> {
+    STORAGE_LATENCY
+      .time(&["select_group"], self.0.select_group(id, group, source))
+      .await
+  }
+
+  async fn toggle_notifications(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    STORAGE_LATENCY
+      .time(&["toggle_notifications"], self.0.toggle_notifications(id, source))
+      .await
+  }
+
+  async fn set_teacher(&self, id: ChatId, teacher: Option<&str>, source: ChangeSource) -> Result<(), BotError> {
+    STORAGE_LATENCY
+      .time(&["set_teacher"], self.0.set_teacher(id, teacher, source))
+      .await
+  }
+
+  async fn toggle_edit_in_place(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    STORAGE_LATENCY
+      .time(&["toggle_edit_in_place"], self.0.toggle_edit_in_place(id, source))
+      .await
+  }
+
+  async fn set_quiet_hours(&self, id: ChatId, quiet_hours: Option<QuietHours>, source: ChangeSource) -> Result<(), BotError> {
+    STORAGE_LATENCY
+      .time(&["set_quiet_hours"], self.0.set_quiet_hours(id, quiet_hours, source))
+      .await
+  }
+
+  async fn set_unreachable(&self, id: ChatId, unreachable: bool, source: ChangeSource) -> Result<bool, BotError> {
+    STORAGE_LATENCY
+      .time(&["set_unreachable"], self.0.set_unreachable(id, unreachable, source))
+      .await
+  }
+
+  async fn history(&self, id: ChatId, limit: i64) -> Result<Vec<Change>, BotError> {
+    STORAGE_LATENCY.time(&["history"], self.0.history(id, limit)).await
+  }
+
+  async fn notifiables(&self) -> Result<Vec<Notifiable>, BotError> {
+    STORAGE_LATENCY.time(&["notifiables"], self.0.notifiables()).await
+  }
+
+  async fn fetch_all(&self) -> Result<Vec<Settings>, BotError> {
+    STORAGE_LATENCY.time(&["fetch_all"], self.0.fetch_all()).await
+  }
+
+  async fn fetch_all_notifiable_ids(&self) -> Result<Vec<i64>, BotError> {
+    STORAGE_LATENCY
+      .time(&["fetch_all_notifiable_ids"], self.0.fetch_all_notifiable_ids())
+      .await
+  }
+
+  async fn fetch_edit_in_place_ids(&self) -> Result<Vec<i64>, BotError> {
+    STORAGE_LATENCY
+      .time(&["fetch_edit_in_place_ids"], self.0.fetch_edit_in_place_ids())
+      .await
+  }
+
+  async fn fetch_quiet_hours(&self) -> Result<HashMap<i64, QuietHours>, BotError> {
+    STORAGE_LATENCY
+      .time(&["fetch_quiet_hours"], self.0.fetch_quiet_hours())
+      .await
+  }
+
+  async fn import(&self, users: &[Settings]) -> Result<(), BotError> {
+    STORAGE_LATENCY.time(&["import"], self.0.import(users)).await
+  }
+}
+
+#[async_trait]
+impl OutboxStore for Instrumented {
+  async fn push_outbox(&self, items: &[OutboxItem]) -> Result<(), BotError> {
+    STORAGE_LATENCY
+      .time(&["push_outbox"], self.0.push_outbox(items))
+      .await
+  }
+
+  async fn finish_outbox(&self, key: &str, status: OutboxStatus) -> Result<(), BotError> {
+    STORAGE_LATENCY
+      .time(&["finish_outbox"], self.0.finish_outbox(key, status))
+      .await
+  }
+
+  async fn pending_outbox(&self) -> Result<Vec<OutboxItem>, BotError> {
+    STORAGE_LATENCY
+      .time(&["pending_outbox"], self.0.pending_outbox())
+      .await
+  }
+
+  async fn deferred_outbox(&self, due: DateTime) -> Result<Vec<OutboxItem>, BotError> {
+    STORAGE_LATENCY
+      .time(&["deferred_outbox"], self.0.deferred_outbox(due))
+      .await
+  }
+
+  async fn supersede_outbox(&self, chat_ids: &[ChatId], target: &str) -> Result<u64, BotError> {
+    STORAGE_LATENCY
+      .time(&["supersede_outbox"], self.0.supersede_outbox(chat_ids, target))
+      .await
+  }
+
+  async fn purge_outbox(&self, before: DateTime) -> Result<u64, BotError> {
+    STORAGE_LATENCY
+      .time(&["purge_outbox"], self.0.purge_outbox(before))
+      .await
+  }
+}
+
+#[async_trait]
+impl DeliveryLogStore for Instrumented {
+  async fn log_delivery(&self, record: &DeliveryRecord) -> Result<(), BotError> {
+    STORAGE_LATENCY
+      .time(&["log_delivery"], self.0.log_delivery(record))
+      .await
+  }
+
+  async fn deliveries(&self, chat_id: ChatId, limit: i64) -> Result<Vec<DeliveryRecord>, BotError> {
+    STORAGE_LATENCY
+      .time(&["deliveries"], self.0.deliveries(chat_id, limit))
+      .await
+  }
+
+  async fn last_messages(&self, target: &str, group: &str) -> Result<HashMap<i64, i32>, BotError> {
+    STORAGE_LATENCY
+      .time(&["last_messages"], self.0.last_messages(target, group))
+      .await
+  }
+
+  async fn last_notified(&self, chat_id: ChatId) -> Result<Option<DeliveryRecord>, BotError> {
+    STORAGE_LATENCY
+      .time(&["last_notified"], self.0.last_notified(chat_id))
+      .await
+  }
+
+  async fn purge_deliveries(&self, before: DateTime) -> Result<u64, BotError> {
+    STORAGE_LATENCY
+      .time(&["purge_deliveries"], self.0.purge_deliveries(before))
+      .await
+  }
+}
+
+#[async_trait]
+impl SnapshotStore for Instrumented {
+  async fn seen_groups(&self, date: &str) -> Result<Vec<SeenGroup>, BotError> {
+    STORAGE_LATENCY.time(&["seen_groups"], self.0.seen_groups(date)).await
+  }
+
+  async fn save_seen(&self, groups: &[SeenGroup]) -> Result<(), BotError> {
+    STORAGE_LATENCY.time(&["save_seen"], self.0.save_seen(groups)).await
+  }
+
+  async fn purge_seen(&self, before: &str) -> Result<u64, BotError> {
+    STORAGE_LATENCY.time(&["purge_seen"], self.0.purge_seen(before)).await
+  }
+}
+
+#[async_trait]
+impl StatsStore for Instrumented {
+  async fn touch(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    STORAGE_LATENCY.time(&["touch"], self.0.touch(id, source)).await
+  }
+
+  async fn count_command(&self, command: &str) -> Result<(), BotError> {
+    STORAGE_LATENCY
+      .time(&["count_command"], self.0.count_command(command))
+      .await
+  }
+
+  async fn command_usage(&self) -> Result<BTreeMap<String, u64>, BotError> {
+    STORAGE_LATENCY.time(&["command_usage"], self.0.command_usage()).await
+  }
+}
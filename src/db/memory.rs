@@ -0,0 +1,549 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use maiq_shared::utils::time::now;
+use mongodb::bson::DateTime;
+use teloxide::types::ChatId;
+
+use crate::{
+  db::{
+    Change, ChangeSource, DeliveryLogStore, DeliveryOutcome, DeliveryRecord, Notifiable, OutboxItem, OutboxStatus, OutboxStore,
+    SeenGroup, Settings, SettingsStore, SnapshotStore, StatsStore, Storage,
+  },
+  error::BotError,
+  quiet_hours::QuietHours,
+};
+
+/// Non-persistent storage for tests and local development. Everything is lost on restart.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+  settings: Arc<RwLock<BTreeMap<i64, Settings>>>,
+  history: Arc<RwLock<Vec<Change>>>,
+  outbox: Arc<RwLock<Vec<OutboxItem>>>,
+  deliveries: Arc<RwLock<Vec<DeliveryRecord>>>,
+  seen: Arc<RwLock<BTreeMap<(String, String), SeenGroup>>>,
+  command_usage: Arc<RwLock<BTreeMap<String, u64>>>,
+}
+
+impl MemoryStore {
+  fn modify<T>(&self, id: ChatId, f: impl FnOnce(&mut Settings) -> T) -> Result<T, BotError> {
+    match self.settings.write().unwrap().get_mut(&id.0) {
+      Some(user) => Ok(f(user)),
+      None => Err(BotError::UserNotFound(id.0)),
+    }
+  }
+
+  fn record(&self, changes: impl IntoIterator<Item = Change>) {
+    self.history.write().unwrap().extend(changes)
+  }
+}
+
+#[async_trait]
+impl Storage for MemoryStore {
+  async fn ping(&self) -> Result<(), BotError> {
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl SettingsStore for MemoryStore {
+  async fn get(&self, id: ChatId) -> Result<Option<Settings>, BotError> {
+    Ok(self.settings.read().unwrap().get(&id.0).cloned())
+  }
+
+  async fn get_or_new(&self, id: ChatId) -> Result<Settings, BotError> {
+    let mut settings = self.settings.write().unwrap();
+    let user = settings.entry(id.0).or_insert_with(|| {
+      info!("New user-id {}", id.0);
+      Settings::new(id)
+    });
+    Ok(user.clone())
+  }
+
+  async fn delete(&self, id: ChatId) -> Result<bool, BotError> {
+    self.history.write().unwrap().retain(|c| c.id != id.0);
+    self.deliveries.write().unwrap().retain(|d| d.chat_id != id.0);
+    self.outbox.write().unwrap().retain(|i| i.chat_id != id.0);
+    Ok(self.settings.write().unwrap().remove(&id.0).is_some())
+  }
+
+  async fn select_group(&self, id: ChatId, group: &str, source: ChangeSource) -> Result<(), BotError> {
+    let old = self.modify(id, |user| {
+      let old = user.clone();
+      user.group = Some(group.into());
+      user.is_notifications_enabled = true;
+      old
+    })?;
+
+    self.record([
+      Change::new(id, "group", old.group.as_deref(), Some(group), source),
+      Change::new(id, "is_notifications_enabled", Some(old.is_notifications_enabled), Some(true), source),
+    ]);
+    Ok(())
+  }
+
+  async fn toggle_notifications(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    let enabled = self.modify(id, |user| {
+      user.is_notifications_enabled = !user.is_notifications_enabled;
+      user.is_notifications_enabled
+    })?;
+
+    self.record([Change::new(id, "is_notifications_enabled", Some(!enabled), Some(enabled), source)]);
+    Ok(enabled)
+  }
+
+  async fn set_teacher(&self, id: ChatId, teacher: Option<&str>, source: ChangeSource) -> Result<(), BotError> {
+    let old = self.modify(id, |user| std::mem::replace(&mut user.teacher, teacher.map(Into::into)))?;
+    self.record([Change::new(id, "teacher", old.as_deref(), teacher, source)]);
+    Ok(())
+  }
+
+  async fn toggle_edit_in_place(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    let enabled = self.modify(id, |user| {
+      user.is_edit_in_place = !user.is_edit_in_place;
+      user.is_edit_in_place
+    })?;
+
+    self.record([Change::new(id, "is_edit_in_place", Some(!enabled), Some(enabled), source)]);
+    Ok(enabled)
+  }
+
+  async fn set_quiet_hours(&self, id: ChatId, quiet_hours: Option<QuietHours>, source: ChangeSource) -> Result<(), BotError> {
+    let old = self.modify(id, |user| std::mem::replace(&mut user.quiet_hours, quiet_hours))?;
+    self.record([Change::new(id, "quiet_hours", old, quiet_hours, source)]);
+    Ok(())
+  }
+
+  async fn set_unreachable(&self, id: ChatId, unreachable: bool, source: ChangeSource) -> Result<bool, BotError> {
+    let changed = self.modify(id, |user| unreachable != std::mem::replace(&mut user.is_unreachable, unreachable));
+    match changed {
+      Ok(true) => {
+        self.record([Change::new(id, "is_unreachable", Some(!unreachable), Some(unreachable), source)]);
+        Ok(true)
+      }
+      Ok(false) | Err(BotError::UserNotFound(_)) => Ok(false),
+      Err(err) => Err(err),
+    }
+  }
+
+  async fn history(&self, id: ChatId, limit: i64) -> Result<Vec<Change>, BotError> {
+    let history = self.history.read().unwrap();
+    Ok(
+      history
+        .iter()
+        .rev()
+        .filter(|c| c.id == id.0)
+        .take(limit as usize)
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn notifiables(&self) -> Result<Vec<Notifiable>, BotError> {
+    let mut notifies: Vec<Notifiable> = vec![];
+    let settings = self.settings.read().unwrap();
+    for user in settings
+      .values()
+      .filter(|u| u.is_notifications_enabled && !u.is_unreachable)
+    {
+      let group = match user.group {
+        Some(ref group) => group,
+        None => continue,
+      };
+
+      match notifies.iter_mut().find(|n| &n.group == group) {
+        Some(n) => n.ids.push(user.id),
+        None => notifies.push(Notifiable::new(group.clone(), user.id)),
+      }
+    }
+
+    Ok(notifies)
+  }
+
+  async fn fetch_all(&self) -> Result<Vec<Settings>, BotError> {
+    Ok(self.settings.read().unwrap().values().cloned().collect())
+  }
+
+  async fn fetch_all_notifiable_ids(&self) -> Result<Vec<i64>, BotError> {
+    let settings = self.settings.read().unwrap();
+    Ok(
+      settings
+        .values()
+        .filter(|u| u.is_notifications_enabled && !u.is_unreachable)
+        .map(|u| u.id)
+        .collect(),
+    )
+  }
+
+  async fn fetch_edit_in_place_ids(&self) -> Result<Vec<i64>, BotError> {
+    let settings = self.settings.read().unwrap();
+    Ok(
+      settings
+        .values()
+        .filter(|u| u.is_edit_in_place)
+        .map(|u| u.id)
+        .collect(),
+    )
+  }
+
+  async fn fetch_quiet_hours(&self) -> Result<HashMap<i64, QuietHours>, BotError> {
+    let settings = self.settings.read().unwrap();
+    Ok(
+      settings
+        .values()
+        .filter_map(|u| u.quiet_hours.map(|q| (u.id, q)))
+        .collect(),
+    )
+  }
+
+  async fn import(&self, users: &[Settings]) -> Result<(), BotError> {
+    let mut settings = self.settings.write().unwrap();
+    settings.extend(users.iter().map(|u| (u.id, u.clone())));
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl OutboxStore for MemoryStore {
+  async fn push_outbox(&self, items: &[OutboxItem]) -> Result<(), BotError> {
+    self.outbox.write().unwrap().extend_from_slice(items);
+    Ok(())
+  }
+
+  async fn finish_outbox(&self, key: &str, status: OutboxStatus) -> Result<(), BotError> {
+    if let Some(item) = self.outbox.write().unwrap().iter_mut().find(|i| i.key == key) {
+      item.status = status;
+    }
+    Ok(())
+  }
+
+  async fn pending_outbox(&self) -> Result<Vec<OutboxItem>, BotError> {
+    let outbox = self.outbox.read().unwrap();
+    let pending = outbox
+      .iter()
+      .filter(|i| i.status == OutboxStatus::Pending && i.not_before.is_none());
+    Ok(pending.cloned().collect())
+  }
+
+  async fn deferred_outbox(&self, due: DateTime) -> Result<Vec<OutboxItem>, BotError> {
+    let outbox = self.outbox.read().unwrap();
+    let deferred = outbox
+      .iter()
+      .filter(|i| i.status == OutboxStatus::Pending && matches!(i.not_before, Some(at) if at <= due));
+    Ok(deferred.cloned().collect())
+  }
+
+  async fn supersede_outbox(&self, chat_ids: &[ChatId], target: &str) -> Result<u64, BotError> {
+    let mut outbox = self.outbox.write().unwrap();
+    let mut superseded = 0;
+    for item in outbox
+      .iter_mut()
+      .filter(|i| chat_ids.contains(&ChatId(i.chat_id)) && i.not_before.is_some())
+    {
+      if item.status == OutboxStatus::Pending && item.target.as_deref() == Some(target) {
+        item.status = OutboxStatus::Superseded;
+        superseded += 1;
+      }
+    }
+    Ok(superseded)
+  }
+
+  async fn purge_outbox(&self, before: DateTime) -> Result<u64, BotError> {
+    let mut outbox = self.outbox.write().unwrap();
+    let len = outbox.len();
+    outbox.retain(|i| i.status == OutboxStatus::Pending || i.created >= before);
+    Ok((len - outbox.len()) as u64)
+  }
+}
+
+#[async_trait]
+impl DeliveryLogStore for MemoryStore {
+  async fn log_delivery(&self, record: &DeliveryRecord) -> Result<(), BotError> {
+    self.deliveries.write().unwrap().push(record.clone());
+    Ok(())
+  }
+
+  async fn deliveries(&self, chat_id: ChatId, limit: i64) -> Result<Vec<DeliveryRecord>, BotError> {
+    let deliveries = self.deliveries.read().unwrap();
+    Ok(
+      deliveries
+        .iter()
+        .rev()
+        .filter(|d| d.chat_id == chat_id.0)
+        .take(limit as usize)
+        .cloned()
+        .collect(),
+    )
+  }
+
+  async fn last_messages(&self, target: &str, group: &str) -> Result<HashMap<i64, i32>, BotError> {
+    let deliveries = self.deliveries.read().unwrap();
+    let latest = deliveries
+      .iter()
+      .filter(|d| d.outcome == DeliveryOutcome::Delivered)
+      .filter(|d| d.target.as_deref() == Some(target) && d.group.as_deref() == Some(group))
+      .filter_map(|d| d.message_id.map(|message_id| (d.chat_id, message_id)));
+    Ok(latest.collect())
+  }
+
+  async fn last_notified(&self, chat_id: ChatId) -> Result<Option<DeliveryRecord>, BotError> {
+    let deliveries = self.deliveries.read().unwrap();
+    Ok(
+      deliveries
+        .iter()
+        .rev()
+        .find(|d| d.chat_id == chat_id.0 && d.outcome == DeliveryOutcome::Delivered && d.snapshot.is_some())
+        .cloned(),
+    )
+  }
+
+  async fn purge_deliveries(&self, before: DateTime) -> Result<u64, BotError> {
+    let mut deliveries = self.deliveries.write().unwrap();
+    let len = deliveries.len();
+    deliveries.retain(|d| d.timestamp >= before);
+    Ok((len - deliveries.len()) as u64)
+  }
+}
+
+#[async_trait]
+impl SnapshotStore for MemoryStore {
+  async fn seen_groups(&self, date: &str) -> Result<Vec<SeenGroup>, BotError> {
+    let seen = self.seen.read().unwrap();
+    Ok(seen.values().filter(|s| s.date == date).cloned().collect())
+  }
+
+  async fn save_seen(&self, groups: &[SeenGroup]) -> Result<(), BotError> {
+    let mut seen = self.seen.write().unwrap();
+    seen.extend(groups.iter().map(|s| ((s.date.clone(), s.group.clone()), s.clone())));
+    Ok(())
+  }
+
+  async fn purge_seen(&self, before: &str) -> Result<u64, BotError> {
+    let mut seen = self.seen.write().unwrap();
+    let len = seen.len();
+    seen.retain(|(date, _), _| date.as_str() >= before);
+    Ok((len - seen.len()) as u64)
+  }
+}
+
+#[async_trait]
+impl StatsStore for MemoryStore {
+  async fn touch(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    let unreachable = self.modify(id, |user| {
+      user.last_seen = Some(DateTime::from_chrono(now()));
+      std::mem::replace(&mut user.is_unreachable, false)
+    });
+    match unreachable {
+      Ok(true) => {
+        self.record([Change::new(id, "is_unreachable", Some(true), Some(false), source)]);
+        Ok(true)
+      }
+      Ok(false) | Err(BotError::UserNotFound(_)) => Ok(false),
+      Err(err) => Err(err),
+    }
+  }
+
+  async fn count_command(&self, command: &str) -> Result<(), BotError> {
+    *self.command_usage.write().unwrap().entry(command.into()).or_default() += 1;
+    Ok(())
+  }
+
+  async fn command_usage(&self) -> Result<BTreeMap<String, u64>, BotError> {
+    Ok(self.command_usage.read().unwrap().clone())
+  }
+}
+
+/// Checks of the storage contract, shared by every backend that can run in tests.
+#[cfg(test)]
+pub(super) mod tests {
+  use super::*;
+
+  fn delivery(id: ChatId) -> DeliveryRecord {
+    DeliveryRecord {
+      chat_id: id.0,
+      snapshot: Some("uid".into()),
+      group: Some("A".into()),
+      target: Some("2023-03-01:Today".into()),
+      message_id: Some(1),
+      timestamp: DateTime::from_chrono(now()),
+      outcome: DeliveryOutcome::Delivered,
+      error: None,
+    }
+  }
+
+  pub async fn check_get_or_new(db: &dyn Storage) {
+    let id = ChatId(1);
+    assert!(db.get(id).await.unwrap().is_none());
+
+    let user = db.get_or_new(id).await.unwrap();
+    db.select_group(id, "A", ChangeSource::Command).await.unwrap();
+    let again = db.get_or_new(id).await.unwrap();
+    assert_eq!(again.joined, user.joined);
+    assert_eq!(again.group.as_deref(), Some("A"));
+    assert_eq!(db.fetch_all().await.unwrap().len(), 1);
+  }
+
+  pub async fn check_toggle(db: &dyn Storage) {
+    let id = ChatId(1);
+    db.get_or_new(id).await.unwrap();
+
+    assert!(db.toggle_notifications(id, ChangeSource::Command).await.unwrap());
+    assert!(db.get(id).await.unwrap().unwrap().is_notifications_enabled);
+    assert!(!db.toggle_notifications(id, ChangeSource::Command).await.unwrap());
+    assert!(!db.get(id).await.unwrap().unwrap().is_notifications_enabled);
+
+    assert!(db.toggle_edit_in_place(id, ChangeSource::Callback).await.unwrap());
+    assert_eq!(db.fetch_edit_in_place_ids().await.unwrap(), vec![1]);
+
+    let history = db.history(id, 10).await.unwrap();
+    let fields: Vec<_> = history.iter().map(|c| (c.field.as_str(), c.new.as_deref())).collect();
+    assert_eq!(
+      fields,
+      vec![
+        ("is_edit_in_place", Some("true")),
+        ("is_notifications_enabled", Some("false")),
+        ("is_notifications_enabled", Some("true")),
+      ]
+    );
+  }
+
+  pub async fn check_notifiables(db: &dyn Storage) {
+    for (id, group) in [(1, "A"), (2, "A"), (3, "B"), (4, "A"), (6, "B")] {
+      db.get_or_new(ChatId(id)).await.unwrap();
+      db.select_group(ChatId(id), group, ChangeSource::Command)
+        .await
+        .unwrap();
+    }
+    db.get_or_new(ChatId(5)).await.unwrap();
+    db.toggle_notifications(ChatId(5), ChangeSource::Command)
+      .await
+      .unwrap();
+    db.toggle_notifications(ChatId(6), ChangeSource::Command)
+      .await
+      .unwrap();
+    assert!(db
+      .set_unreachable(ChatId(4), true, ChangeSource::Delivery)
+      .await
+      .unwrap());
+    assert!(!db
+      .set_unreachable(ChatId(4), true, ChangeSource::Delivery)
+      .await
+      .unwrap());
+
+    let mut notifiables: Vec<_> = db
+      .notifiables()
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|mut n| {
+        n.ids.sort();
+        (n.group, n.ids)
+      })
+      .collect();
+    notifiables.sort();
+    assert_eq!(notifiables, vec![("A".to_string(), vec![1, 2]), ("B".to_string(), vec![3])]);
+
+    let mut ids = db.fetch_all_notifiable_ids().await.unwrap();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2, 3, 5]);
+  }
+
+  pub async fn check_touch(db: &dyn Storage) {
+    let id = ChatId(1);
+    assert!(!db.touch(id, ChangeSource::Command).await.unwrap());
+    assert!(db.get(id).await.unwrap().is_none());
+
+    db.get_or_new(id).await.unwrap();
+    db.set_unreachable(id, true, ChangeSource::Delivery).await.unwrap();
+    assert!(db.touch(id, ChangeSource::Command).await.unwrap());
+    assert!(!db.touch(id, ChangeSource::Command).await.unwrap());
+
+    let user = db.get(id).await.unwrap().unwrap();
+    assert!(!user.is_unreachable);
+    assert!(user.last_seen.is_some());
+    let history = db.history(id, 10).await.unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!((history[0].field.as_str(), history[0].new.as_deref()), ("is_unreachable", Some("false")));
+  }
+
+  pub async fn check_supersede(db: &dyn Storage) {
+    let due = DateTime::from_chrono(now());
+    let item = |id: i64, target: &str| {
+      OutboxItem::new(ChatId(id), target)
+        .with_origin("uid", "A", target)
+        .deferred(due)
+    };
+    let items = [item(1, "today"), item(2, "today"), item(3, "today"), item(1, "next"), OutboxItem::new(ChatId(1), "now")];
+    db.push_outbox(&items).await.unwrap();
+
+    assert_eq!(db.supersede_outbox(&[ChatId(1), ChatId(2)], "today").await.unwrap(), 2);
+    let mut left: Vec<_> = db
+      .deferred_outbox(due)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|i| (i.chat_id, i.text))
+      .collect();
+    left.sort();
+    assert_eq!(left, vec![(1, "next".to_string()), (3, "today".to_string())]);
+    assert_eq!(db.pending_outbox().await.unwrap().len(), 1);
+  }
+
+  pub async fn check_delete(db: &dyn Storage) {
+    let (id, other) = (ChatId(1), ChatId(2));
+    for id in [id, other] {
+      db.get_or_new(id).await.unwrap();
+      db.select_group(id, "A", ChangeSource::Command).await.unwrap();
+      db.log_delivery(&delivery(id)).await.unwrap();
+    }
+    db.push_outbox(&[OutboxItem::new(id, "a"), OutboxItem::new(other, "b")])
+      .await
+      .unwrap();
+
+    assert!(db.delete(id).await.unwrap());
+    assert!(!db.delete(id).await.unwrap());
+
+    assert!(db.get(id).await.unwrap().is_none());
+    assert!(db.history(id, 10).await.unwrap().is_empty());
+    assert!(db.deliveries(id, 10).await.unwrap().is_empty());
+    let pending: Vec<_> = db.pending_outbox().await.unwrap().iter().map(|i| i.chat_id).collect();
+    assert_eq!(pending, vec![other.0]);
+
+    assert!(db.get(other).await.unwrap().is_some());
+    assert_eq!(db.history(other, 10).await.unwrap().len(), 2);
+    assert_eq!(db.deliveries(other, 10).await.unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn get_or_new() {
+    check_get_or_new(&MemoryStore::default()).await;
+  }
+
+  #[tokio::test]
+  async fn toggle() {
+    check_toggle(&MemoryStore::default()).await;
+  }
+
+  #[tokio::test]
+  async fn notifiables() {
+    check_notifiables(&MemoryStore::default()).await;
+  }
+
+  #[tokio::test]
+  async fn touch() {
+    check_touch(&MemoryStore::default()).await;
+  }
+
+  #[tokio::test]
+  async fn supersede() {
+    check_supersede(&MemoryStore::default()).await;
+  }
+
+  #[tokio::test]
+  async fn delete() {
+    check_delete(&MemoryStore::default()).await;
+  }
+}
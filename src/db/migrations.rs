@@ -0,0 +1,170 @@
+use std::{future::Future, pin::Pin};
+
+use mongodb::{
+  bson::{doc, Bson, Document},
+  options::UpdateOptions,
+  Database,
+};
+
+use crate::{db::MongoError, error::BotError};
+
+type MigrationFuture<'a> = Pin<Box<dyn Future<Output = Result<(), MongoError>> + Send + 'a>>;
+
+struct Migration {
+  version: i32,
+  description: &'static str,
+  up: for<'a> fn(&'a Database) -> MigrationFuture<'a>,
+}
+
+/// Ordered list of migrations. Every migration must be idempotent: it may be re-run if the process dies
+/// before the new version is recorded.
+const MIGRATIONS: &[Migration] = &[
+  Migration { version: 1, description: "add missing `teacher` field to users", up: add_teacher_field },
+  Migration { version: 2, description: "merge duplicated users before the unique `id` index", up: merge_duplicated_users },
+  Migration { version: 3, description: "add `is_unreachable` field to users", up: add_unreachable_field },
+  Migration { version: 4, description: "add `is_edit_in_place` field to users", up: add_edit_in_place_field },
+  Migration { version: 5, description: "add `quiet_hours` field to users", up: add_quiet_hours_field },
+  Migration { version: 6, description: "add `last_seen` field to users", up: add_last_seen_field },
+];
+
+const META_COLLECTION: &str = "meta";
+const SCHEMA_DOC_ID: &str = "schema";
+
+pub fn latest_version() -> i32 {
+  MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub async fn run(db: &Database) -> Result<(), BotError> {
+  let current = current_version(db).await?;
+  let latest = latest_version();
+
+  if current > latest {
+    error!("Database schema v{} is newer than the latest known v{}", current, latest);
+    return Err(BotError::UnsupportedSchema { found: current, known: latest });
+  }
+
+  for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+    info!("Applying migration v{}: {}", migration.version, migration.description);
+    (migration.up)(db).await?;
+    set_version(db, migration.version).await?;
+  }
+
+  info!("Database schema is at v{}", latest);
+  Ok(())
+}
+
+async fn current_version(db: &Database) -> Result<i32, MongoError> {
+  let meta = db
+    .collection::<Document>(META_COLLECTION)
+    .find_one(doc! { "_id": SCHEMA_DOC_ID }, None)
+    .await?;
+  Ok(meta.and_then(|m| m.get_i32("version").ok()).unwrap_or(0))
+}
+
+async fn set_version(db: &Database, version: i32) -> Result<(), MongoError> {
+  db.collection::<Document>(META_COLLECTION)
+    .update_one(
+      doc! { "_id": SCHEMA_DOC_ID },
+      doc! { "$set": { "version": version } },
+      UpdateOptions::builder().upsert(true).build(),
+    )
+    .await?;
+  Ok(())
+}
+
+fn add_teacher_field(db: &Database) -> MigrationFuture<'_> {
+  add_field(db, "teacher", Bson::Null)
+}
+
+/// Sets the field of users that don't have it yet to the default.
+fn add_field<'a>(db: &'a Database, name: &'static str, default: Bson) -> MigrationFuture<'a> {
+  Box::pin(async move {
+    let res = db
+      .collection::<Document>("users")
+      .update_many(doc! { name: { "$exists": false } }, doc! { "$set": { name: default } }, None)
+      .await?;
+    info!("Updated {} users", res.modified_count);
+    Ok(())
+  })
+}
+
+fn merge_duplicated_users(db: &Database) -> MigrationFuture<'_> {
+  Box::pin(async move {
+    let users = db.collection::<Document>("users");
+    let pipeline = [
+      doc! { "$sort": { "_id": 1 } },
+      doc! { "$group": { "_id": "$id", "docs": { "$push": "$$ROOT" }, "count": { "$sum": 1 } } },
+      doc! { "$match": { "count": { "$gt": 1 } } },
+    ];
+
+    let mut duplicates = vec![];
+    let mut cur = users.aggregate(pipeline, None).await?;
+    while cur.advance().await? {
+      let docs: Vec<Document> = match cur.deserialize_current()?.get_array("docs") {
+        Ok(docs) => docs.iter().filter_map(Bson::as_document).cloned().collect(),
+        Err(_) => continue,
+      };
+      duplicates.push(docs);
+    }
+
+    for docs in duplicates {
+      let (keep, rest) = match docs.split_first() {
+        Some(x) => x,
+        None => continue,
+      };
+
+      let merged = rest.iter().fold(keep.clone(), merge_user);
+      let rest_ids: Vec<&Bson> = rest.iter().filter_map(|d| d.get("_id")).collect();
+      warn!("Merging {} duplicates of user-id {:?}", rest_ids.len(), keep.get("id"));
+
+      users
+        .replace_one(doc! { "_id": keep.get("_id") }, merged, None)
+        .await?;
+      users.delete_many(doc! { "_id": { "$in": rest_ids } }, None).await?;
+    }
+
+    // `id_1` could be created without the `unique` flag before, it will be recreated at startup
+    users.drop_index("id_1", None).await.ok();
+    Ok(())
+  })
+}
+
+/// Later documents win for non-null fields, notifications stay enabled if any of the duplicates had them.
+fn merge_user(mut merged: Document, other: &Document) -> Document {
+  for key in ["group", "teacher"] {
+    match other.get(key) {
+      Some(Bson::Null) | None => (),
+      Some(value) => {
+        merged.insert(key, value.clone());
+      }
+    }
+  }
+
+  if other.get_bool("is_notifications_enabled").unwrap_or(false) {
+    merged.insert("is_notifications_enabled", true);
+  }
+
+  if let (Ok(joined), Ok(other_joined)) = (merged.get_datetime("joined"), other.get_datetime("joined")) {
+    if other_joined < joined {
+      merged.insert("joined", *other_joined);
+    }
+  }
+
+  merged
+}
+
+fn add_unreachable_field(db: &Database) -> MigrationFuture<'_> {
+  add_field(db, "is_unreachable", Bson::Boolean(false))
+}
+
+fn add_edit_in_place_field(db: &Database) -> MigrationFuture<'_> {
+  add_field(db, "is_edit_in_place", Bson::Boolean(false))
+}
+
+fn add_quiet_hours_field(db: &Database) -> MigrationFuture<'_> {
+  add_field(db, "quiet_hours", Bson::Null)
+}
+
+fn add_last_seen_field(db: &Database) -> MigrationFuture<'_> {
+  add_field(db, "last_seen", Bson::Null)
+}
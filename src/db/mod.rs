@@ -0,0 +1,433 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  str::FromStr,
+  sync::Arc,
+};
+
+use async_trait::async_trait;
+use maiq_shared::utils::time::now;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use teloxide::types::ChatId;
+
+use crate::{env, error::BotError, quiet_hours::QuietHours};
+
+use instrumented::Instrumented;
+pub use memory::MemoryStore;
+pub use mongo::{MongoError, MongoPool};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteError, SqliteStore};
+
+mod instrumented;
+mod memory;
+mod migrations;
+mod mongo;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub type Database = Arc<dyn Storage>;
+
+/// Everything the bot needs from a storage backend.
+#[async_trait]
+pub trait Storage: SettingsStore + OutboxStore + DeliveryLogStore + SnapshotStore + StatsStore {
+  /// Checks that the backend is reachable, used by health checks.
+  async fn ping(&self) -> Result<(), BotError>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Settings {
+  pub id: i64,
+  pub group: Option<String>,
+  pub is_notifications_enabled: bool,
+  pub joined: DateTime,
+  pub teacher: Option<String>,
+  /// Set when Telegram refuses delivery (blocked bot, deleted account), cleared on the next interaction.
+  #[serde(default)]
+  pub is_unreachable: bool,
+  /// Edit the previous notification for the same day instead of sending a new one.
+  #[serde(default)]
+  pub is_edit_in_place: bool,
+  /// Notifications are deferred until the end of the window.
+  #[serde(default)]
+  pub quiet_hours: Option<QuietHours>,
+  /// Last interaction with the bot, `None` if there were none since it is recorded.
+  #[serde(default)]
+  pub last_seen: Option<DateTime>,
+}
+
+#[derive(Debug)]
+pub struct Notifiable {
+  pub group: String,
+  pub ids: Vec<i64>,
+}
+
+impl Notifiable {
+  pub fn new(group: String, id: i64) -> Self {
+    Notifiable { group, ids: vec![id] }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeSource {
+  Command,
+  Callback,
+  Delivery,
+}
+
+/// Audit record of a single settings field mutation.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Change {
+  pub id: i64,
+  pub field: String,
+  pub old: Option<String>,
+  pub new: Option<String>,
+  pub source: ChangeSource,
+  pub timestamp: DateTime,
+}
+
+impl Change {
+  pub fn new<T: ToString>(id: ChatId, field: &str, old: Option<T>, new: Option<T>, source: ChangeSource) -> Self {
+    Self {
+      id: id.0,
+      field: field.into(),
+      old: old.map(|x| x.to_string()),
+      new: new.map(|x| x.to_string()),
+      source,
+      timestamp: DateTime::from_chrono(now()),
+    }
+  }
+}
+
+impl ChangeSource {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ChangeSource::Command => "command",
+      ChangeSource::Callback => "callback",
+      ChangeSource::Delivery => "delivery",
+    }
+  }
+}
+
+impl FromStr for ChangeSource {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "command" => Ok(ChangeSource::Command),
+      "callback" => Ok(ChangeSource::Callback),
+      "delivery" => Ok(ChangeSource::Delivery),
+      _ => Err(()),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboxStatus {
+  Pending,
+  Delivered,
+  Failed,
+  /// Deferred and replaced by a newer version before being sent
+  Superseded,
+}
+
+/// Outgoing message persisted before sending, so it survives restarts.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxItem {
+  pub key: String,
+  pub chat_id: i64,
+  pub text: String,
+  pub created: DateTime,
+  pub status: OutboxStatus,
+  /// Uid of the snapshot this notification is about, `None` for broadcasts
+  pub snapshot: Option<String>,
+  pub group: Option<String>,
+  /// Date and [`Fetch`](maiq_shared::Fetch) of the snapshot, notifications with the same target may replace each other
+  pub target: Option<String>,
+  /// Message to edit instead of sending a new one
+  pub edit_message_id: Option<i32>,
+  /// Deferred items are sent once they are due, see [`Settings::quiet_hours`]
+  pub not_before: Option<DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOutcome {
+  Delivered,
+  Failed,
+  /// Telegram refused delivery for good, see [`Settings::is_unreachable`]
+  Unreachable,
+}
+
+/// Log entry of a single notification sent to a user.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeliveryRecord {
+  pub chat_id: i64,
+  pub snapshot: Option<String>,
+  pub group: Option<String>,
+  pub target: Option<String>,
+  pub message_id: Option<i32>,
+  pub timestamp: DateTime,
+  pub outcome: DeliveryOutcome,
+  pub error: Option<String>,
+}
+
+impl OutboxItem {
+  pub fn new<T: Into<String>>(chat_id: ChatId, text: T) -> Self {
+    let created = DateTime::from_chrono(now());
+    Self {
+      key: format!("{:x}-{:016x}", created.timestamp_millis(), fastrand::u64(..)),
+      chat_id: chat_id.0,
+      text: text.into(),
+      created,
+      status: OutboxStatus::Pending,
+      snapshot: None,
+      group: None,
+      target: None,
+      edit_message_id: None,
+      not_before: None,
+    }
+  }
+
+  pub fn with_origin(mut self, snapshot: &str, group: &str, target: &str) -> Self {
+    self.snapshot = Some(snapshot.into());
+    self.group = Some(group.into());
+    self.target = Some(target.into());
+    self
+  }
+
+  pub fn editing(mut self, message_id: i32) -> Self {
+    self.edit_message_id = Some(message_id);
+    self
+  }
+
+  pub fn deferred(mut self, not_before: DateTime) -> Self {
+    self.not_before = Some(not_before);
+    self
+  }
+}
+
+impl OutboxStatus {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      OutboxStatus::Pending => "pending",
+      OutboxStatus::Delivered => "delivered",
+      OutboxStatus::Failed => "failed",
+      OutboxStatus::Superseded => "superseded",
+    }
+  }
+}
+
+impl FromStr for OutboxStatus {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "pending" => Ok(OutboxStatus::Pending),
+      "delivered" => Ok(OutboxStatus::Delivered),
+      "failed" => Ok(OutboxStatus::Failed),
+      "superseded" => Ok(OutboxStatus::Superseded),
+      _ => Err(()),
+    }
+  }
+}
+
+/// Version of a group timetable for a date that users were last notified about.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeenGroup {
+  /// `%Y-%m-%d`
+  pub date: String,
+  pub group: String,
+  /// Uid of the snapshot the group came from
+  pub snapshot: String,
+  /// The group serialized to json, so it can be compared and diffed later
+  pub content: String,
+  pub updated: DateTime,
+}
+
+impl DeliveryOutcome {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DeliveryOutcome::Delivered => "delivered",
+      DeliveryOutcome::Failed => "failed",
+      DeliveryOutcome::Unreachable => "unreachable",
+    }
+  }
+}
+
+impl FromStr for DeliveryOutcome {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "delivered" => Ok(DeliveryOutcome::Delivered),
+      "failed" => Ok(DeliveryOutcome::Failed),
+      "unreachable" => Ok(DeliveryOutcome::Unreachable),
+      _ => Err(()),
+    }
+  }
+}
+
+impl Settings {
+  pub fn new(id: ChatId) -> Self {
+    Self {
+      id: id.0,
+      is_notifications_enabled: false,
+      joined: DateTime::from_chrono(now()),
+      group: None,
+      teacher: None,
+      is_unreachable: false,
+      is_edit_in_place: false,
+      quiet_hours: None,
+      last_seen: Some(DateTime::from_chrono(now())),
+    }
+  }
+}
+
+#[async_trait]
+pub trait SettingsStore: Send + Sync {
+  async fn get(&self, id: ChatId) -> Result<Option<Settings>, BotError>;
+
+  /// Creates the user if needed. Only `/start` should call it, so forgotten users are not recreated implicitly.
+  async fn get_or_new(&self, id: ChatId) -> Result<Settings, BotError>;
+
+  /// Permanently deletes everything stored for the user, including the history, the outbox and the delivery log. Returns `false` if there was nothing to delete.
+  async fn delete(&self, id: ChatId) -> Result<bool, BotError>;
+
+  /// Sets the group and enables notifications for it.
+  async fn select_group(&self, id: ChatId, group: &str, source: ChangeSource) -> Result<(), BotError>;
+
+  /// Flips notifications and returns the stored state.
+  async fn toggle_notifications(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError>;
+
+  async fn set_teacher(&self, id: ChatId, teacher: Option<&str>, source: ChangeSource) -> Result<(), BotError>;
+
+  /// Flips the edit-in-place mode and returns the stored state.
+  async fn toggle_edit_in_place(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError>;
+
+  async fn set_quiet_hours(&self, id: ChatId, quiet_hours: Option<QuietHours>, source: ChangeSource) -> Result<(), BotError>;
+
+  /// Returns `true` if the flag has actually changed. Unreachable users are excluded from notifications.
+  async fn set_unreachable(&self, id: ChatId, unreachable: bool, source: ChangeSource) -> Result<bool, BotError>;
+
+  /// Latest changes of the user settings, newest first.
+  async fn history(&self, id: ChatId, limit: i64) -> Result<Vec<Change>, BotError>;
+
+  async fn notifiables(&self) -> Result<Vec<Notifiable>, BotError>;
+
+  async fn fetch_all(&self) -> Result<Vec<Settings>, BotError>;
+
+  async fn fetch_all_notifiable_ids(&self) -> Result<Vec<i64>, BotError>;
+
+  async fn fetch_edit_in_place_ids(&self) -> Result<Vec<i64>, BotError>;
+
+  async fn fetch_quiet_hours(&self) -> Result<HashMap<i64, QuietHours>, BotError>;
+
+  /// Inserts users as is, replacing existing ones with the same ids.
+  async fn import(&self, users: &[Settings]) -> Result<(), BotError>;
+}
+
+#[async_trait]
+pub trait OutboxStore: Send + Sync {
+  async fn push_outbox(&self, items: &[OutboxItem]) -> Result<(), BotError>;
+
+  async fn finish_outbox(&self, key: &str, status: OutboxStatus) -> Result<(), BotError>;
+
+  /// Undelivered items that are not deferred, oldest first.
+  async fn pending_outbox(&self) -> Result<Vec<OutboxItem>, BotError>;
+
+  /// Undelivered deferred items that are due by the date, oldest first.
+  async fn deferred_outbox(&self, due: DateTime) -> Result<Vec<OutboxItem>, BotError>;
+
+  /// Marks undelivered deferred items of the chats about the target as superseded, so only the latest version is sent.
+  async fn supersede_outbox(&self, chat_ids: &[ChatId], target: &str) -> Result<u64, BotError>;
+
+  /// Drops finished items created before the date.
+  async fn purge_outbox(&self, before: DateTime) -> Result<u64, BotError>;
+}
+
+#[async_trait]
+pub trait DeliveryLogStore: Send + Sync {
+  async fn log_delivery(&self, record: &DeliveryRecord) -> Result<(), BotError>;
+
+  /// Latest log entries of the user, newest first.
+  async fn deliveries(&self, chat_id: ChatId, limit: i64) -> Result<Vec<DeliveryRecord>, BotError>;
+
+  /// Message ids of the latest delivered notifications about the target for the group, per chat.
+  async fn last_messages(&self, target: &str, group: &str) -> Result<HashMap<i64, i32>, BotError>;
+
+  /// The latest successfully delivered notification about a snapshot.
+  async fn last_notified(&self, chat_id: ChatId) -> Result<Option<DeliveryRecord>, BotError>;
+
+  /// Drops entries older than the date.
+  async fn purge_deliveries(&self, before: DateTime) -> Result<u64, BotError>;
+}
+
+#[async_trait]
+pub trait SnapshotStore: Send + Sync {
+  async fn seen_groups(&self, date: &str) -> Result<Vec<SeenGroup>, BotError>;
+
+  /// Replaces stored versions of the same date and group.
+  async fn save_seen(&self, groups: &[SeenGroup]) -> Result<(), BotError>;
+
+  /// Drops versions for dates before the date.
+  async fn purge_seen(&self, before: &str) -> Result<u64, BotError>;
+}
+
+#[async_trait]
+pub trait StatsStore: Send + Sync {
+  /// Records an interaction of the user now and clears [`Settings::is_unreachable`] in the same write.
+  /// Returns `true` if the user was unreachable, unknown users are ignored.
+  async fn touch(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError>;
+
+  async fn count_command(&self, command: &str) -> Result<(), BotError>;
+
+  /// Usage counts of commands since they are recorded.
+  async fn command_usage(&self) -> Result<BTreeMap<String, u64>, BotError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+  Mongo,
+  #[cfg(feature = "sqlite")]
+  Sqlite,
+  Memory,
+}
+
+impl FromStr for StorageKind {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "mongo" | "mongodb" => Ok(StorageKind::Mongo),
+      #[cfg(feature = "sqlite")]
+      "sqlite" => Ok(StorageKind::Sqlite),
+      "memory" | "in-memory" => Ok(StorageKind::Memory),
+      _ => Err(()),
+    }
+  }
+}
+
+impl StorageKind {
+  pub fn from_env() -> Self {
+    match env::var(env::DB_KIND) {
+      Some(kind) => kind
+        .parse()
+        .unwrap_or_else(|_| panic!("Unknown or disabled storage kind `{}`, check enabled features", kind)),
+      None => StorageKind::Mongo,
+    }
+  }
+}
+
+pub async fn connect() -> Result<Database, BotError> {
+  let kind = StorageKind::from_env();
+  info!("Using {:?} storage", kind);
+  let db: Database = match kind {
+    StorageKind::Mongo => Arc::new(MongoPool::init().await?),
+    #[cfg(feature = "sqlite")]
+    StorageKind::Sqlite => Arc::new(SqliteStore::init()?),
+    StorageKind::Memory => Arc::new(MemoryStore::default()),
+  };
+  Ok(Arc::new(Instrumented(db)))
+}
@@ -0,0 +1,589 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  ops::Deref,
+};
+
+use async_trait::async_trait;
+use maiq_shared::utils::time::now;
+use mongodb::{
+  bson::{doc, to_bson, to_document, DateTime, Document},
+  error::{ErrorKind, WriteFailure},
+  options::{
+    ClientOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReplaceOptions, ReturnDocument,
+    UpdateOptions,
+  },
+  Collection, IndexModel,
+};
+use teloxide::types::ChatId;
+
+use crate::{
+  db::{
+    migrations, Change, ChangeSource, DeliveryLogStore, DeliveryOutcome, DeliveryRecord, Notifiable, OutboxItem, OutboxStatus,
+    OutboxStore, SeenGroup, Settings, SettingsStore, SnapshotStore, StatsStore, Storage,
+  },
+  env,
+  error::BotError,
+  quiet_hours::QuietHours,
+};
+
+pub type Mongo = mongodb::Client;
+pub type MongoError = mongodb::error::Error;
+
+#[derive(Clone)]
+pub struct MongoPool {
+  mongo: Mongo,
+  settings: Collection<Settings>,
+  history: Collection<Change>,
+  outbox: Collection<OutboxItem>,
+  deliveries: Collection<DeliveryRecord>,
+  seen: Collection<SeenGroup>,
+  command_usage: Collection<Document>,
+}
+
+impl Deref for MongoPool {
+  type Target = Mongo;
+
+  fn deref(&self) -> &Self::Target {
+    &self.mongo
+  }
+}
+
+impl MongoPool {
+  pub async fn init() -> Result<Self, BotError> {
+    let url = env::var(env::DB_URL).unwrap();
+    info!("Connecting to database");
+    let mut opts = ClientOptions::parse(url).await?;
+    opts.app_name = Some("maiq-bot".into());
+    opts.default_database = Some(env::var(env::DEFAULT_DB).unwrap());
+    let mongo = Mongo::with_options(opts)?;
+    let db = mongo.default_database().unwrap();
+    migrations::run(&db).await?;
+    let settings = db.collection("users");
+    let history = db.collection("history");
+    let outbox = db.collection("outbox");
+    let deliveries = db.collection("deliveries");
+    let seen = db.collection("seen_groups");
+    let command_usage = db.collection("command_usage");
+    let pool = Self { mongo, settings, history, outbox, deliveries, seen, command_usage };
+    pool.create_indexes().await?;
+    Ok(pool)
+  }
+
+  /// Returns the settings as they were before the update.
+  async fn update_existing(&self, id: ChatId, update: Document) -> Result<Settings, BotError> {
+    let opts = FindOneAndUpdateOptions::builder()
+      .return_document(ReturnDocument::Before)
+      .build();
+    match self
+      .settings
+      .find_one_and_update(doc! { "id": id.0 }, update, opts)
+      .await?
+    {
+      Some(old) => Ok(old),
+      None => Err(BotError::UserNotFound(id.0)),
+    }
+  }
+
+  /// Flips a boolean field and returns the settings after the update.
+  async fn toggle(&self, id: ChatId, field: &str) -> Result<Settings, BotError> {
+    let update = vec![doc! { "$set": { field: { "$not": format!("${}", field) } } }];
+    let opts = FindOneAndUpdateOptions::builder()
+      .return_document(ReturnDocument::After)
+      .build();
+    match self
+      .settings
+      .find_one_and_update(doc! { "id": id.0 }, update, opts)
+      .await?
+    {
+      Some(settings) => Ok(settings),
+      None => Err(BotError::UserNotFound(id.0)),
+    }
+  }
+
+  /// Oldest first.
+  async fn find_outbox(&self, filter: Document) -> Result<Vec<OutboxItem>, BotError> {
+    let opts = FindOptions::builder().sort(doc! { "created": 1 }).build();
+    let mut result = vec![];
+    let mut cur = self.outbox.find(filter, opts).await?;
+    while cur.advance().await? {
+      result.push(cur.deserialize_current()?);
+    }
+    Ok(result)
+  }
+
+  async fn record(&self, changes: &[Change]) -> Result<(), MongoError> {
+    self.history.insert_many(changes, None).await?;
+    Ok(())
+  }
+
+  async fn create_indexes(&self) -> Result<(), MongoError> {
+    let indexes = [
+      IndexModel::builder()
+        .keys(doc! { "id": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build(),
+      IndexModel::builder().keys(doc! { "group": 1 }).build(),
+      IndexModel::builder()
+        .keys(doc! { "is_notifications_enabled": 1, "group": 1 })
+        .build(),
+    ];
+    self.settings.create_indexes(indexes, None).await?;
+
+    let index = IndexModel::builder().keys(doc! { "id": 1, "timestamp": -1 }).build();
+    self.history.create_index(index, None).await?;
+
+    let index = IndexModel::builder()
+      .keys(doc! { "key": 1 })
+      .options(IndexOptions::builder().unique(true).build())
+      .build();
+    self.outbox.create_index(index, None).await?;
+    let index = IndexModel::builder().keys(doc! { "status": 1, "created": 1 }).build();
+    self.outbox.create_index(index, None).await?;
+
+    let index = IndexModel::builder()
+      .keys(doc! { "chat_id": 1, "timestamp": -1 })
+      .build();
+    self.deliveries.create_index(index, None).await?;
+    let index = IndexModel::builder()
+      .keys(doc! { "target": 1, "group": 1, "timestamp": 1 })
+      .build();
+    self.deliveries.create_index(index, None).await?;
+
+    let index = IndexModel::builder()
+      .keys(doc! { "date": 1, "group": 1 })
+      .options(IndexOptions::builder().unique(true).build())
+      .build();
+    self.seen.create_index(index, None).await?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Storage for MongoPool {
+  async fn ping(&self) -> Result<(), BotError> {
+    self
+      .mongo
+      .default_database()
+      .unwrap()
+      .run_command(doc! { "ping": 1 }, None)
+      .await?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl SettingsStore for MongoPool {
+  async fn get(&self, id: ChatId) -> Result<Option<Settings>, BotError> {
+    Ok(self.settings.find_one(doc! { "id": id.0 }, None).await?)
+  }
+
+  async fn get_or_new(&self, id: ChatId) -> Result<Settings, BotError> {
+    let filter = doc! { "id": id.0 };
+    let mut insert = to_document(&Settings::new(id)).map_err(MongoError::from)?;
+    insert.remove("id");
+
+    let opts = UpdateOptions::builder().upsert(true).build();
+    match self
+      .settings
+      .update_one(filter.clone(), doc! { "$setOnInsert": insert }, opts)
+      .await
+    {
+      Ok(res) if res.upserted_id.is_some() => info!("New user-id {}", id.0),
+      Ok(_) => (),
+      // Concurrent upsert of the same user lost the race against the unique index, the document exists now
+      Err(err) if is_duplicate_key(&err) => (),
+      Err(err) => return Err(err.into()),
+    }
+
+    match self.settings.find_one(filter, None).await? {
+      Some(settings) => Ok(settings),
+      None => Err(BotError::MongoError(format!("user-id {} vanished after upsert", id.0))),
+    }
+  }
+
+  async fn delete(&self, id: ChatId) -> Result<bool, BotError> {
+    let res = self.settings.delete_many(doc! { "id": id.0 }, None).await?;
+    self.history.delete_many(doc! { "id": id.0 }, None).await?;
+    self.deliveries.delete_many(doc! { "chat_id": id.0 }, None).await?;
+    self.outbox.delete_many(doc! { "chat_id": id.0 }, None).await?;
+    info!("Deleted user-id {}", id.0);
+    Ok(res.deleted_count > 0)
+  }
+
+  async fn select_group(&self, id: ChatId, group: &str, source: ChangeSource) -> Result<(), BotError> {
+    let update = doc! { "$set": { "group": group, "is_notifications_enabled": true } };
+    let old = self.update_existing(id, update).await?;
+    self
+      .record(&[
+        Change::new(id, "group", old.group.as_deref(), Some(group), source),
+        Change::new(id, "is_notifications_enabled", Some(old.is_notifications_enabled), Some(true), source),
+      ])
+      .await?;
+    Ok(())
+  }
+
+  async fn toggle_notifications(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    let enabled = self
+      .toggle(id, "is_notifications_enabled")
+      .await?
+      .is_notifications_enabled;
+    self
+      .record(&[Change::new(id, "is_notifications_enabled", Some(!enabled), Some(enabled), source)])
+      .await?;
+    Ok(enabled)
+  }
+
+  async fn set_teacher(&self, id: ChatId, teacher: Option<&str>, source: ChangeSource) -> Result<(), BotError> {
+    let old = self
+      .update_existing(id, doc! { "$set": { "teacher": teacher } })
+      .await?;
+    self
+      .record(&[Change::new(id, "teacher", old.teacher.as_deref(), teacher, source)])
+      .await?;
+    Ok(())
+  }
+
+  async fn toggle_edit_in_place(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    let enabled = self.toggle(id, "is_edit_in_place").await?.is_edit_in_place;
+    self
+      .record(&[Change::new(id, "is_edit_in_place", Some(!enabled), Some(enabled), source)])
+      .await?;
+    Ok(enabled)
+  }
+
+  async fn set_quiet_hours(&self, id: ChatId, quiet_hours: Option<QuietHours>, source: ChangeSource) -> Result<(), BotError> {
+    let value = to_bson(&quiet_hours).map_err(MongoError::from)?;
+    let old = self
+      .update_existing(id, doc! { "$set": { "quiet_hours": value } })
+      .await?;
+    self
+      .record(&[Change::new(id, "quiet_hours", old.quiet_hours, quiet_hours, source)])
+      .await?;
+    Ok(())
+  }
+
+  async fn set_unreachable(&self, id: ChatId, unreachable: bool, source: ChangeSource) -> Result<bool, BotError> {
+    let res = self
+      .settings
+      .update_one(doc! { "id": id.0, "is_unreachable": !unreachable }, doc! { "$set": { "is_unreachable": unreachable } }, None)
+      .await?;
+
+    if res.modified_count == 0 {
+      return Ok(false);
+    }
+
+    self
+      .record(&[Change::new(id, "is_unreachable", Some(!unreachable), Some(unreachable), source)])
+      .await?;
+    Ok(true)
+  }
+
+  async fn history(&self, id: ChatId, limit: i64) -> Result<Vec<Change>, BotError> {
+    let opts = FindOptions::builder()
+      .sort(doc! { "timestamp": -1 })
+      .limit(limit)
+      .build();
+    let mut result = vec![];
+    let mut cur = self.history.find(doc! { "id": id.0 }, opts).await?;
+    while cur.advance().await? {
+      result.push(cur.deserialize_current()?);
+    }
+    Ok(result)
+  }
+
+  async fn notifiables(&self) -> Result<Vec<Notifiable>, BotError> {
+    info!("Colleting notifiable users");
+    let pipeline = [
+      doc! { "$match": { "is_notifications_enabled": true, "is_unreachable": { "$ne": true }, "group": { "$ne": null } } },
+      doc! {
+        "$group": {
+          "_id": {
+            "$cond": [
+              { "$and": [{ "$eq": [{ "$type": "$id" }, "long"] }, { "$eq": [{ "$type": "$group" }, "string"] }] },
+              "$group",
+              null
+            ]
+          },
+          "ids": { "$push": "$id" },
+          "count": { "$sum": 1 }
+        }
+      },
+    ];
+
+    let mut notifies: Vec<Notifiable> = vec![];
+    let mut cur = self.settings.aggregate(pipeline, None).await?;
+    while cur.advance().await? {
+      let raw = cur.current();
+      match raw.get_str("_id") {
+        Ok(group) => {
+          let ids = raw
+            .get_array("ids")
+            .into_iter()
+            .flatten()
+            .filter_map(|id| id.ok()?.as_i64())
+            .collect();
+          notifies.push(Notifiable { group: group.to_string(), ids })
+        }
+        Err(_) => warn!("Skipped {} notifiable users with a malformed `id` or `group`", raw.get_i32("count").unwrap_or_default()),
+      }
+    }
+
+    Ok(notifies)
+  }
+
+  async fn fetch_all(&self) -> Result<Vec<Settings>, BotError> {
+    let mut result = vec![];
+    let mut cur = self.settings.find(doc! {}, None).await?;
+    while cur.advance().await? {
+      result.push(cur.deserialize_current()?);
+    }
+
+    Ok(result)
+  }
+
+  async fn fetch_all_notifiable_ids(&self) -> Result<Vec<i64>, BotError> {
+    let mut result = vec![];
+    let mut cur = self
+      .settings
+      .find(doc! { "is_notifications_enabled": true, "is_unreachable": { "$ne": true } }, None)
+      .await?;
+
+    while cur.advance().await? {
+      let id = match cur.current().get_i64("id") {
+        Ok(id) => id,
+        Err(_) => continue,
+      };
+
+      result.push(id);
+    }
+    Ok(result)
+  }
+
+  async fn fetch_edit_in_place_ids(&self) -> Result<Vec<i64>, BotError> {
+    let mut result = vec![];
+    let mut cur = self.settings.find(doc! { "is_edit_in_place": true }, None).await?;
+    while cur.advance().await? {
+      if let Ok(id) = cur.current().get_i64("id") {
+        result.push(id);
+      }
+    }
+    Ok(result)
+  }
+
+  async fn fetch_quiet_hours(&self) -> Result<HashMap<i64, QuietHours>, BotError> {
+    let mut result = HashMap::new();
+    let mut cur = self
+      .settings
+      .find(doc! { "quiet_hours": { "$ne": null } }, None)
+      .await?;
+    while cur.advance().await? {
+      let user = cur.deserialize_current()?;
+      if let Some(quiet_hours) = user.quiet_hours {
+        result.insert(user.id, quiet_hours);
+      }
+    }
+    Ok(result)
+  }
+
+  async fn import(&self, users: &[Settings]) -> Result<(), BotError> {
+    let opts = ReplaceOptions::builder().upsert(true).build();
+    for user in users {
+      self
+        .settings
+        .replace_one(doc! { "id": user.id }, user, opts.clone())
+        .await?;
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl OutboxStore for MongoPool {
+  async fn push_outbox(&self, items: &[OutboxItem]) -> Result<(), BotError> {
+    if !items.is_empty() {
+      self.outbox.insert_many(items, None).await?;
+    }
+    Ok(())
+  }
+
+  async fn finish_outbox(&self, key: &str, status: OutboxStatus) -> Result<(), BotError> {
+    self
+      .outbox
+      .update_one(doc! { "key": key }, doc! { "$set": { "status": status.as_str() } }, None)
+      .await?;
+    Ok(())
+  }
+
+  async fn pending_outbox(&self) -> Result<Vec<OutboxItem>, BotError> {
+    let filter = doc! { "status": OutboxStatus::Pending.as_str(), "not_before": null };
+    self.find_outbox(filter).await
+  }
+
+  async fn deferred_outbox(&self, due: DateTime) -> Result<Vec<OutboxItem>, BotError> {
+    let filter = doc! { "status": OutboxStatus::Pending.as_str(), "not_before": { "$lte": due } };
+    self.find_outbox(filter).await
+  }
+
+  async fn supersede_outbox(&self, chat_ids: &[ChatId], target: &str) -> Result<u64, BotError> {
+    let chat_ids: Vec<i64> = chat_ids.iter().map(|id| id.0).collect();
+    let filter = doc! {
+      "chat_id": { "$in": chat_ids },
+      "target": target,
+      "status": OutboxStatus::Pending.as_str(),
+      "not_before": { "$ne": null },
+    };
+    let update = doc! { "$set": { "status": OutboxStatus::Superseded.as_str() } };
+    Ok(self.outbox.update_many(filter, update, None).await?.modified_count)
+  }
+
+  async fn purge_outbox(&self, before: DateTime) -> Result<u64, BotError> {
+    let filter = doc! { "status": { "$ne": OutboxStatus::Pending.as_str() }, "created": { "$lt": before } };
+    Ok(self.outbox.delete_many(filter, None).await?.deleted_count)
+  }
+}
+
+#[async_trait]
+impl DeliveryLogStore for MongoPool {
+  async fn log_delivery(&self, record: &DeliveryRecord) -> Result<(), BotError> {
+    self.deliveries.insert_one(record, None).await?;
+    Ok(())
+  }
+
+  async fn deliveries(&self, chat_id: ChatId, limit: i64) -> Result<Vec<DeliveryRecord>, BotError> {
+    let opts = FindOptions::builder()
+      .sort(doc! { "timestamp": -1 })
+      .limit(limit)
+      .build();
+    let mut result = vec![];
+    let mut cur = self.deliveries.find(doc! { "chat_id": chat_id.0 }, opts).await?;
+    while cur.advance().await? {
+      result.push(cur.deserialize_current()?);
+    }
+    Ok(result)
+  }
+
+  async fn last_messages(&self, target: &str, group: &str) -> Result<HashMap<i64, i32>, BotError> {
+    let pipeline = [
+      doc! { "$match": {
+        "target": target,
+        "group": group,
+        "outcome": DeliveryOutcome::Delivered.as_str(),
+        "message_id": { "$ne": null },
+      } },
+      doc! { "$sort": { "timestamp": 1 } },
+      doc! { "$group": { "_id": "$chat_id", "message_id": { "$last": "$message_id" } } },
+    ];
+
+    let mut result = HashMap::new();
+    let mut cur = self.deliveries.aggregate(pipeline, None).await?;
+    while cur.advance().await? {
+      let raw = cur.current();
+      if let (Ok(chat_id), Ok(message_id)) = (raw.get_i64("_id"), raw.get_i32("message_id")) {
+        result.insert(chat_id, message_id);
+      }
+    }
+    Ok(result)
+  }
+
+  async fn last_notified(&self, chat_id: ChatId) -> Result<Option<DeliveryRecord>, BotError> {
+    let filter = doc! {
+      "chat_id": chat_id.0,
+      "outcome": DeliveryOutcome::Delivered.as_str(),
+      "snapshot": { "$ne": null },
+    };
+    let opts = FindOneOptions::builder().sort(doc! { "timestamp": -1 }).build();
+    Ok(self.deliveries.find_one(filter, opts).await?)
+  }
+
+  async fn purge_deliveries(&self, before: DateTime) -> Result<u64, BotError> {
+    Ok(
+      self
+        .deliveries
+        .delete_many(doc! { "timestamp": { "$lt": before } }, None)
+        .await?
+        .deleted_count,
+    )
+  }
+}
+
+#[async_trait]
+impl SnapshotStore for MongoPool {
+  async fn seen_groups(&self, date: &str) -> Result<Vec<SeenGroup>, BotError> {
+    let mut result = vec![];
+    let mut cur = self.seen.find(doc! { "date": date }, None).await?;
+    while cur.advance().await? {
+      result.push(cur.deserialize_current()?);
+    }
+    Ok(result)
+  }
+
+  async fn save_seen(&self, groups: &[SeenGroup]) -> Result<(), BotError> {
+    let opts = ReplaceOptions::builder().upsert(true).build();
+    for seen in groups {
+      self
+        .seen
+        .replace_one(doc! { "date": &seen.date, "group": &seen.group }, seen, opts.clone())
+        .await?;
+    }
+    Ok(())
+  }
+
+  async fn purge_seen(&self, before: &str) -> Result<u64, BotError> {
+    Ok(
+      self
+        .seen
+        .delete_many(doc! { "date": { "$lt": before } }, None)
+        .await?
+        .deleted_count,
+    )
+  }
+}
+
+fn is_duplicate_key(err: &MongoError) -> bool {
+  const DUPLICATE_KEY: i32 = 11000;
+  match *err.kind {
+    ErrorKind::Write(WriteFailure::WriteError(ref e)) => e.code == DUPLICATE_KEY,
+    ErrorKind::Command(ref e) => e.code == DUPLICATE_KEY,
+    _ => false,
+  }
+}
+
+#[async_trait]
+impl StatsStore for MongoPool {
+  async fn touch(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    let update = doc! { "$set": { "last_seen": DateTime::from_chrono(now()), "is_unreachable": false } };
+    let old = match self.update_existing(id, update).await {
+      Ok(old) => old,
+      Err(BotError::UserNotFound(_)) => return Ok(false),
+      Err(err) => return Err(err),
+    };
+
+    if old.is_unreachable {
+      self
+        .record(&[Change::new(id, "is_unreachable", Some(true), Some(false), source)])
+        .await?;
+    }
+    Ok(old.is_unreachable)
+  }
+
+  async fn count_command(&self, command: &str) -> Result<(), BotError> {
+    let opts = UpdateOptions::builder().upsert(true).build();
+    self
+      .command_usage
+      .update_one(doc! { "_id": command }, doc! { "$inc": { "count": 1i64 } }, opts)
+      .await?;
+    Ok(())
+  }
+
+  async fn command_usage(&self) -> Result<BTreeMap<String, u64>, BotError> {
+    let mut usage = BTreeMap::new();
+    let mut cur = self.command_usage.find(None, None).await?;
+    while cur.advance().await? {
+      let doc = cur.deserialize_current()?;
+      if let (Ok(command), Ok(count)) = (doc.get_str("_id"), doc.get_i64("count")) {
+        usage.insert(command.to_string(), count as u64);
+      }
+    }
+    Ok(usage)
+  }
+}
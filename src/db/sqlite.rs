@@ -0,0 +1,787 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use maiq_shared::utils::time::now;
+use mongodb::bson::DateTime;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use teloxide::types::ChatId;
+
+use crate::{
+  db::{
+    Change, ChangeSource, DeliveryLogStore, DeliveryOutcome, DeliveryRecord, Notifiable, OutboxItem, OutboxStatus, OutboxStore,
+    SeenGroup, Settings, SettingsStore, SnapshotStore, StatsStore, Storage,
+  },
+  env,
+  error::BotError,
+  quiet_hours::QuietHours,
+};
+
+pub type SqliteError = rusqlite::Error;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS users (
+  id INTEGER PRIMARY KEY NOT NULL,
+  "group" TEXT,
+  is_notifications_enabled INTEGER NOT NULL DEFAULT 0,
+  joined INTEGER NOT NULL,
+  teacher TEXT
+);
+CREATE INDEX IF NOT EXISTS users_notifiable ON users (is_notifications_enabled, "group");
+
+CREATE TABLE IF NOT EXISTS history (
+  id INTEGER NOT NULL,
+  field TEXT NOT NULL,
+  old TEXT,
+  new TEXT,
+  source TEXT NOT NULL,
+  timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS history_id ON history (id, timestamp);
+
+CREATE TABLE IF NOT EXISTS outbox (
+  key TEXT PRIMARY KEY NOT NULL,
+  chat_id INTEGER NOT NULL,
+  text TEXT NOT NULL,
+  created INTEGER NOT NULL,
+  status TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS outbox_status ON outbox (status, created);
+
+CREATE TABLE IF NOT EXISTS deliveries (
+  chat_id INTEGER NOT NULL,
+  snapshot TEXT,
+  "group" TEXT,
+  message_id INTEGER,
+  timestamp INTEGER NOT NULL,
+  outcome TEXT NOT NULL,
+  error TEXT
+);
+CREATE INDEX IF NOT EXISTS deliveries_chat_id ON deliveries (chat_id, timestamp);
+
+CREATE TABLE IF NOT EXISTS seen_groups (
+  date TEXT NOT NULL,
+  "group" TEXT NOT NULL,
+  snapshot TEXT NOT NULL,
+  content TEXT NOT NULL,
+  updated INTEGER NOT NULL,
+  PRIMARY KEY (date, "group")
+);
+"#;
+
+/// Applied in order on top of [`SCHEMA`], `PRAGMA user_version` holds the number of applied ones.
+const MIGRATIONS: &[&str] = &[
+  "ALTER TABLE users ADD COLUMN is_unreachable INTEGER NOT NULL DEFAULT 0",
+  r#"ALTER TABLE outbox ADD COLUMN snapshot TEXT; ALTER TABLE outbox ADD COLUMN "group" TEXT;"#,
+  "ALTER TABLE users ADD COLUMN is_edit_in_place INTEGER NOT NULL DEFAULT 0;
+   ALTER TABLE outbox ADD COLUMN target TEXT;
+   ALTER TABLE outbox ADD COLUMN edit_message_id INTEGER;
+   ALTER TABLE deliveries ADD COLUMN target TEXT;",
+  "ALTER TABLE users ADD COLUMN quiet_hours TEXT; ALTER TABLE outbox ADD COLUMN not_before INTEGER;",
+  "ALTER TABLE users ADD COLUMN last_seen INTEGER;
+   CREATE TABLE IF NOT EXISTS command_usage (command TEXT PRIMARY KEY NOT NULL, count INTEGER NOT NULL DEFAULT 0);",
+  r#"CREATE INDEX IF NOT EXISTS deliveries_target ON deliveries (target, "group", timestamp);"#,
+];
+
+const SETTINGS_COLUMNS: &str =
+  r#"id, "group", is_notifications_enabled, joined, teacher, is_unreachable, is_edit_in_place, quiet_hours, last_seen"#;
+
+/// Embedded storage for single-host deployments. `DATABASE_CONNECTION_URL` is a path to the database file,
+/// optionally prefixed with `sqlite://`.
+#[derive(Clone)]
+pub struct SqliteStore {
+  conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+  pub fn init() -> Result<Self, BotError> {
+    let url = env::var(env::DB_URL).unwrap();
+    let path = url.strip_prefix("sqlite://").unwrap_or(&url);
+    Self::open(path)
+  }
+
+  pub fn open(path: &str) -> Result<Self, BotError> {
+    info!("Opening sqlite database at {}", path);
+    Self::with_connection(Connection::open(path)?)
+  }
+
+  /// Brings the schema of the database up to date.
+  fn with_connection(conn: Connection) -> Result<Self, BotError> {
+    conn.execute_batch(SCHEMA)?;
+    migrate(&conn)?;
+    Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+  }
+
+  async fn call<T, F>(&self, f: F) -> Result<T, BotError>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, SqliteError> + Send + 'static,
+  {
+    let conn = self.conn.clone();
+    let res = tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+      .await
+      .expect("sqlite task panicked")?;
+    Ok(res)
+  }
+
+  /// Runs the update with the current settings of the user and records returned changes.
+  async fn update_existing<T, F>(&self, id: ChatId, f: F) -> Result<T, BotError>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Connection, Settings) -> Result<(T, Vec<Change>), SqliteError> + Send + 'static,
+  {
+    let res = self
+      .call(move |conn| {
+        let tx = conn.unchecked_transaction()?;
+        let old = match select_settings(&tx, id.0)? {
+          Some(old) => old,
+          None => return Ok(None),
+        };
+        let (res, changes) = f(&tx, old)?;
+        record(&tx, &changes)?;
+        tx.commit()?;
+        Ok(Some(res))
+      })
+      .await?;
+
+    res.ok_or(BotError::UserNotFound(id.0))
+  }
+}
+
+fn migrate(conn: &Connection) -> Result<(), BotError> {
+  let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+  let latest = MIGRATIONS.len() as i32;
+  if version > latest {
+    error!("Database schema v{} is newer than the latest known v{}", version, latest);
+    return Err(BotError::UnsupportedSchema { found: version, known: latest });
+  }
+
+  for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+    info!("Applying sqlite migration v{}", idx + 1);
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(migration)?;
+    tx.pragma_update(None, "user_version", idx + 1)?;
+    tx.commit()?;
+  }
+  Ok(())
+}
+
+fn record(conn: &Connection, changes: &[Change]) -> Result<(), SqliteError> {
+  let mut stmt =
+    conn.prepare_cached("INSERT INTO history (id, field, old, new, source, timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+  for c in changes {
+    stmt.execute(params![c.id, c.field, c.old, c.new, c.source.as_str(), c.timestamp.timestamp_millis()])?;
+  }
+  Ok(())
+}
+
+fn read_outbox_item(row: &Row) -> Result<OutboxItem, SqliteError> {
+  let status: String = row.get("status")?;
+  Ok(OutboxItem {
+    key: row.get("key")?,
+    chat_id: row.get("chat_id")?,
+    text: row.get("text")?,
+    created: DateTime::from_millis(row.get("created")?),
+    status: status.parse().unwrap_or(OutboxStatus::Failed),
+    snapshot: row.get("snapshot")?,
+    group: row.get("group")?,
+    target: row.get("target")?,
+    edit_message_id: row.get("edit_message_id")?,
+    not_before: row.get::<_, Option<i64>>("not_before")?.map(DateTime::from_millis),
+  })
+}
+
+fn read_delivery(row: &Row) -> Result<DeliveryRecord, SqliteError> {
+  let outcome: String = row.get("outcome")?;
+  Ok(DeliveryRecord {
+    chat_id: row.get("chat_id")?,
+    snapshot: row.get("snapshot")?,
+    group: row.get("group")?,
+    target: row.get("target")?,
+    message_id: row.get("message_id")?,
+    timestamp: DateTime::from_millis(row.get("timestamp")?),
+    outcome: outcome.parse().unwrap_or(DeliveryOutcome::Failed),
+    error: row.get("error")?,
+  })
+}
+
+fn read_change(row: &Row) -> Result<Change, SqliteError> {
+  let source: String = row.get("source")?;
+  Ok(Change {
+    id: row.get("id")?,
+    field: row.get("field")?,
+    old: row.get("old")?,
+    new: row.get("new")?,
+    source: source.parse().unwrap_or(ChangeSource::Command),
+    timestamp: DateTime::from_millis(row.get("timestamp")?),
+  })
+}
+
+fn read_settings(row: &Row) -> Result<Settings, SqliteError> {
+  Ok(Settings {
+    id: row.get("id")?,
+    group: row.get("group")?,
+    is_notifications_enabled: row.get("is_notifications_enabled")?,
+    joined: DateTime::from_millis(row.get("joined")?),
+    teacher: row.get("teacher")?,
+    is_unreachable: row.get("is_unreachable")?,
+    is_edit_in_place: row.get("is_edit_in_place")?,
+    quiet_hours: row
+      .get::<_, Option<String>>("quiet_hours")?
+      .and_then(|q| q.parse().ok()),
+    last_seen: row.get::<_, Option<i64>>("last_seen")?.map(DateTime::from_millis),
+  })
+}
+
+fn select_settings(conn: &Connection, id: i64) -> Result<Option<Settings>, SqliteError> {
+  conn
+    .query_row(&format!("SELECT {} FROM users WHERE id = ?1", SETTINGS_COLUMNS), [id], read_settings)
+    .optional()
+}
+
+#[async_trait]
+impl Storage for SqliteStore {
+  async fn ping(&self) -> Result<(), BotError> {
+    self.call(|conn| conn.execute_batch("SELECT 1")).await
+  }
+}
+
+#[async_trait]
+impl SettingsStore for SqliteStore {
+  async fn get(&self, id: ChatId) -> Result<Option<Settings>, BotError> {
+    self.call(move |conn| select_settings(conn, id.0)).await
+  }
+
+  async fn get_or_new(&self, id: ChatId) -> Result<Settings, BotError> {
+    self
+      .call(move |conn| {
+        let user = Settings::new(id);
+        let inserted = conn.execute(
+          "INSERT OR IGNORE INTO users (id, is_notifications_enabled, joined, last_seen) VALUES (?1, ?2, ?3, ?4)",
+          params![
+            user.id,
+            user.is_notifications_enabled,
+            user.joined.timestamp_millis(),
+            user.last_seen.map(|at| at.timestamp_millis())
+          ],
+        )?;
+
+        if inserted > 0 {
+          info!("New user-id {}", user.id);
+        }
+
+        select_settings(conn, id.0).map(|s| s.unwrap_or(user))
+      })
+      .await
+  }
+
+  async fn delete(&self, id: ChatId) -> Result<bool, BotError> {
+    let deleted = self
+      .call(move |conn| {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM history WHERE id = ?1", [id.0])?;
+        tx.execute("DELETE FROM deliveries WHERE chat_id = ?1", [id.0])?;
+        tx.execute("DELETE FROM outbox WHERE chat_id = ?1", [id.0])?;
+        let deleted = tx.execute("DELETE FROM users WHERE id = ?1", [id.0])?;
+        tx.commit()?;
+        Ok(deleted)
+      })
+      .await?;
+    Ok(deleted > 0)
+  }
+
+  async fn select_group(&self, id: ChatId, group: &str, source: ChangeSource) -> Result<(), BotError> {
+    let group = group.to_string();
+    self
+      .update_existing(id, move |conn, old| {
+        conn.execute(r#"UPDATE users SET "group" = ?2, is_notifications_enabled = 1 WHERE id = ?1"#, params![id.0, group])?;
+        let changes = vec![
+          Change::new(id, "group", old.group, Some(group), source),
+          Change::new(id, "is_notifications_enabled", Some(old.is_notifications_enabled), Some(true), source),
+        ];
+        Ok(((), changes))
+      })
+      .await
+  }
+
+  async fn toggle_notifications(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    self
+      .update_existing(id, move |conn, old| {
+        conn.execute("UPDATE users SET is_notifications_enabled = NOT is_notifications_enabled WHERE id = ?1", [id.0])?;
+        let enabled = !old.is_notifications_enabled;
+        Ok((enabled, vec![Change::new(id, "is_notifications_enabled", Some(!enabled), Some(enabled), source)]))
+      })
+      .await
+  }
+
+  async fn set_teacher(&self, id: ChatId, teacher: Option<&str>, source: ChangeSource) -> Result<(), BotError> {
+    let teacher = teacher.map(String::from);
+    self
+      .update_existing(id, move |conn, old| {
+        conn.execute("UPDATE users SET teacher = ?2 WHERE id = ?1", params![id.0, teacher])?;
+        Ok(((), vec![Change::new(id, "teacher", old.teacher, teacher, source)]))
+      })
+      .await
+  }
+
+  async fn toggle_edit_in_place(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    self
+      .update_existing(id, move |conn, old| {
+        conn.execute("UPDATE users SET is_edit_in_place = NOT is_edit_in_place WHERE id = ?1", [id.0])?;
+        let enabled = !old.is_edit_in_place;
+        Ok((enabled, vec![Change::new(id, "is_edit_in_place", Some(!enabled), Some(enabled), source)]))
+      })
+      .await
+  }
+
+  async fn set_quiet_hours(&self, id: ChatId, quiet_hours: Option<QuietHours>, source: ChangeSource) -> Result<(), BotError> {
+    self
+      .update_existing(id, move |conn, old| {
+        conn.execute("UPDATE users SET quiet_hours = ?2 WHERE id = ?1", params![id.0, quiet_hours.map(|q| q.to_string())])?;
+        Ok(((), vec![Change::new(id, "quiet_hours", old.quiet_hours, quiet_hours, source)]))
+      })
+      .await
+  }
+
+  async fn set_unreachable(&self, id: ChatId, unreachable: bool, source: ChangeSource) -> Result<bool, BotError> {
+    self
+      .call(move |conn| {
+        let tx = conn.unchecked_transaction()?;
+        let changed =
+          tx.execute("UPDATE users SET is_unreachable = ?2 WHERE id = ?1 AND is_unreachable != ?2", params![id.0, unreachable])?;
+        if changed > 0 {
+          record(&tx, &[Change::new(id, "is_unreachable", Some(!unreachable), Some(unreachable), source)])?;
+        }
+        tx.commit()?;
+        Ok(changed > 0)
+      })
+      .await
+  }
+
+  async fn history(&self, id: ChatId, limit: i64) -> Result<Vec<Change>, BotError> {
+    self
+      .call(move |conn| {
+        let mut stmt = conn.prepare("SELECT * FROM history WHERE id = ?1 ORDER BY timestamp DESC LIMIT ?2")?;
+        let rows = stmt.query_map([id.0, limit], read_change)?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn notifiables(&self) -> Result<Vec<Notifiable>, BotError> {
+    info!("Colleting notifiable users");
+    self
+      .call(|conn| {
+        let mut stmt = conn.prepare(
+          r#"SELECT "group", group_concat(id) FROM users
+             WHERE is_notifications_enabled = 1 AND is_unreachable = 0 AND "group" IS NOT NULL
+             GROUP BY "group""#,
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+          let group: String = row.get(0)?;
+          let ids: String = row.get(1)?;
+          Ok(Notifiable { group, ids: ids.split(',').filter_map(|id| id.parse().ok()).collect() })
+        })?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn fetch_all(&self) -> Result<Vec<Settings>, BotError> {
+    self
+      .call(|conn| {
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM users", SETTINGS_COLUMNS))?;
+        let rows = stmt.query_map([], read_settings)?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn fetch_all_notifiable_ids(&self) -> Result<Vec<i64>, BotError> {
+    self
+      .call(|conn| {
+        let mut stmt = conn.prepare("SELECT id FROM users WHERE is_notifications_enabled = 1 AND is_unreachable = 0")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn fetch_edit_in_place_ids(&self) -> Result<Vec<i64>, BotError> {
+    self
+      .call(|conn| {
+        let mut stmt = conn.prepare("SELECT id FROM users WHERE is_edit_in_place = 1")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn fetch_quiet_hours(&self) -> Result<HashMap<i64, QuietHours>, BotError> {
+    self
+      .call(|conn| {
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM users WHERE quiet_hours IS NOT NULL", SETTINGS_COLUMNS))?;
+        let rows = stmt.query_map([], read_settings)?;
+        let users = rows.collect::<Result<Vec<_>, _>>()?;
+        Ok(
+          users
+            .into_iter()
+            .filter_map(|u| u.quiet_hours.map(|q| (u.id, q)))
+            .collect(),
+        )
+      })
+      .await
+  }
+
+  async fn import(&self, users: &[Settings]) -> Result<(), BotError> {
+    let users = users.to_vec();
+    self
+      .call(move |conn| {
+        let tx = conn.unchecked_transaction()?;
+        for user in users {
+          tx.execute(
+            &format!("INSERT OR REPLACE INTO users ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)", SETTINGS_COLUMNS),
+            params![
+              user.id,
+              user.group,
+              user.is_notifications_enabled,
+              user.joined.timestamp_millis(),
+              user.teacher,
+              user.is_unreachable,
+              user.is_edit_in_place,
+              user.quiet_hours.map(|q| q.to_string()),
+              user.last_seen.map(|at| at.timestamp_millis())
+            ],
+          )?;
+        }
+        tx.commit()
+      })
+      .await
+  }
+}
+
+#[async_trait]
+impl OutboxStore for SqliteStore {
+  async fn push_outbox(&self, items: &[OutboxItem]) -> Result<(), BotError> {
+    let items = items.to_vec();
+    self
+      .call(move |conn| {
+        let tx = conn.unchecked_transaction()?;
+        {
+          let mut stmt = tx.prepare_cached(
+            r#"INSERT INTO outbox (key, chat_id, text, created, status, snapshot, "group", target, edit_message_id, not_before)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"#,
+          )?;
+          for i in items {
+            stmt.execute(params![
+              i.key,
+              i.chat_id,
+              i.text,
+              i.created.timestamp_millis(),
+              i.status.as_str(),
+              i.snapshot,
+              i.group,
+              i.target,
+              i.edit_message_id,
+              i.not_before.map(|at| at.timestamp_millis())
+            ])?;
+          }
+        }
+        tx.commit()
+      })
+      .await
+  }
+
+  async fn finish_outbox(&self, key: &str, status: OutboxStatus) -> Result<(), BotError> {
+    let key = key.to_string();
+    self
+      .call(move |conn| conn.execute("UPDATE outbox SET status = ?2 WHERE key = ?1", params![key, status.as_str()]))
+      .await?;
+    Ok(())
+  }
+
+  async fn pending_outbox(&self) -> Result<Vec<OutboxItem>, BotError> {
+    self
+      .call(|conn| {
+        let mut stmt = conn.prepare("SELECT * FROM outbox WHERE status = ?1 AND not_before IS NULL ORDER BY created")?;
+        let rows = stmt.query_map([OutboxStatus::Pending.as_str()], read_outbox_item)?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn deferred_outbox(&self, due: DateTime) -> Result<Vec<OutboxItem>, BotError> {
+    self
+      .call(move |conn| {
+        let mut stmt = conn.prepare("SELECT * FROM outbox WHERE status = ?1 AND not_before <= ?2 ORDER BY created")?;
+        let rows = stmt.query_map(params![OutboxStatus::Pending.as_str(), due.timestamp_millis()], read_outbox_item)?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn supersede_outbox(&self, chat_ids: &[ChatId], target: &str) -> Result<u64, BotError> {
+    let chat_ids = serde_json::to_string(&chat_ids.iter().map(|id| id.0).collect::<Vec<_>>()).unwrap();
+    let target = target.to_string();
+    let superseded = self
+      .call(move |conn| {
+        conn.execute(
+          "UPDATE outbox SET status = ?1
+           WHERE chat_id IN (SELECT value FROM json_each(?2)) AND target = ?3 AND status = ?4 AND not_before IS NOT NULL",
+          params![OutboxStatus::Superseded.as_str(), chat_ids, target, OutboxStatus::Pending.as_str()],
+        )
+      })
+      .await?;
+    Ok(superseded as u64)
+  }
+
+  async fn purge_outbox(&self, before: DateTime) -> Result<u64, BotError> {
+    let purged = self
+      .call(move |conn| {
+        conn.execute(
+          "DELETE FROM outbox WHERE status != ?1 AND created < ?2",
+          params![OutboxStatus::Pending.as_str(), before.timestamp_millis()],
+        )
+      })
+      .await?;
+    Ok(purged as u64)
+  }
+}
+
+#[async_trait]
+impl DeliveryLogStore for SqliteStore {
+  async fn log_delivery(&self, record: &DeliveryRecord) -> Result<(), BotError> {
+    let r = record.clone();
+    self
+      .call(move |conn| {
+        conn.execute(
+          r#"INSERT INTO deliveries (chat_id, snapshot, "group", target, message_id, timestamp, outcome, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"#,
+          params![
+            r.chat_id,
+            r.snapshot,
+            r.group,
+            r.target,
+            r.message_id,
+            r.timestamp.timestamp_millis(),
+            r.outcome.as_str(),
+            r.error
+          ],
+        )
+      })
+      .await?;
+    Ok(())
+  }
+
+  async fn deliveries(&self, chat_id: ChatId, limit: i64) -> Result<Vec<DeliveryRecord>, BotError> {
+    self
+      .call(move |conn| {
+        let mut stmt = conn.prepare("SELECT * FROM deliveries WHERE chat_id = ?1 ORDER BY timestamp DESC LIMIT ?2")?;
+        let rows = stmt.query_map([chat_id.0, limit], read_delivery)?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn last_messages(&self, target: &str, group: &str) -> Result<HashMap<i64, i32>, BotError> {
+    let (target, group) = (target.to_string(), group.to_string());
+    self
+      .call(move |conn| {
+        let mut stmt = conn.prepare(
+          r#"SELECT chat_id, message_id FROM deliveries
+             WHERE target = ?1 AND "group" = ?2 AND outcome = ?3 AND message_id IS NOT NULL
+             ORDER BY timestamp"#,
+        )?;
+        let rows =
+          stmt.query_map(params![target, group, DeliveryOutcome::Delivered.as_str()], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn last_notified(&self, chat_id: ChatId) -> Result<Option<DeliveryRecord>, BotError> {
+    self
+      .call(move |conn| {
+        conn
+          .query_row(
+            "SELECT * FROM deliveries WHERE chat_id = ?1 AND outcome = ?2 AND snapshot IS NOT NULL
+             ORDER BY timestamp DESC LIMIT 1",
+            params![chat_id.0, DeliveryOutcome::Delivered.as_str()],
+            read_delivery,
+          )
+          .optional()
+      })
+      .await
+  }
+
+  async fn purge_deliveries(&self, before: DateTime) -> Result<u64, BotError> {
+    let purged = self
+      .call(move |conn| conn.execute("DELETE FROM deliveries WHERE timestamp < ?1", [before.timestamp_millis()]))
+      .await?;
+    Ok(purged as u64)
+  }
+}
+
+#[async_trait]
+impl SnapshotStore for SqliteStore {
+  async fn seen_groups(&self, date: &str) -> Result<Vec<SeenGroup>, BotError> {
+    let date = date.to_string();
+    self
+      .call(move |conn| {
+        let mut stmt = conn.prepare("SELECT * FROM seen_groups WHERE date = ?1")?;
+        let rows = stmt.query_map([date], |row| {
+          Ok(SeenGroup {
+            date: row.get("date")?,
+            group: row.get("group")?,
+            snapshot: row.get("snapshot")?,
+            content: row.get("content")?,
+            updated: DateTime::from_millis(row.get("updated")?),
+          })
+        })?;
+        rows.collect()
+      })
+      .await
+  }
+
+  async fn save_seen(&self, groups: &[SeenGroup]) -> Result<(), BotError> {
+    let groups = groups.to_vec();
+    self
+      .call(move |conn| {
+        let tx = conn.unchecked_transaction()?;
+        {
+          let mut stmt = tx.prepare_cached(
+            r#"INSERT OR REPLACE INTO seen_groups (date, "group", snapshot, content, updated) VALUES (?1, ?2, ?3, ?4, ?5)"#,
+          )?;
+          for s in groups {
+            stmt.execute(params![s.date, s.group, s.snapshot, s.content, s.updated.timestamp_millis()])?;
+          }
+        }
+        tx.commit()
+      })
+      .await
+  }
+
+  async fn purge_seen(&self, before: &str) -> Result<u64, BotError> {
+    let before = before.to_string();
+    let purged = self
+      .call(move |conn| conn.execute("DELETE FROM seen_groups WHERE date < ?1", [before]))
+      .await?;
+    Ok(purged as u64)
+  }
+}
+
+#[async_trait]
+impl StatsStore for SqliteStore {
+  async fn touch(&self, id: ChatId, source: ChangeSource) -> Result<bool, BotError> {
+    let now = DateTime::from_chrono(now()).timestamp_millis();
+    let res = self
+      .update_existing(id, move |conn, old| {
+        conn.execute("UPDATE users SET last_seen = ?2, is_unreachable = 0 WHERE id = ?1", params![id.0, now])?;
+        let changes = match old.is_unreachable {
+          true => vec![Change::new(id, "is_unreachable", Some(true), Some(false), source)],
+          false => vec![],
+        };
+        Ok((old.is_unreachable, changes))
+      })
+      .await;
+
+    match res {
+      Err(BotError::UserNotFound(_)) => Ok(false),
+      res => res,
+    }
+  }
+
+  async fn count_command(&self, command: &str) -> Result<(), BotError> {
+    let command = command.to_string();
+    self
+      .call(move |conn| {
+        conn.execute(
+          "INSERT INTO command_usage (command, count) VALUES (?1, 1) ON CONFLICT (command) DO UPDATE SET count = count + 1",
+          [command],
+        )?;
+        Ok(())
+      })
+      .await
+  }
+
+  async fn command_usage(&self) -> Result<BTreeMap<String, u64>, BotError> {
+    self
+      .call(|conn| {
+        let mut stmt = conn.prepare("SELECT command, count FROM command_usage")?;
+        let usage = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?;
+        usage.collect()
+      })
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::memory::tests::*;
+
+  fn store() -> SqliteStore {
+    SqliteStore::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+  }
+
+  #[test]
+  fn migrations() {
+    let store = store();
+    let conn = store.conn.lock().unwrap();
+    let version: usize = conn
+      .pragma_query_value(None, "user_version", |row| row.get(0))
+      .unwrap();
+    assert_eq!(version, MIGRATIONS.len());
+
+    migrate(&conn).unwrap();
+    let query = format!("SELECT {} FROM users", SETTINGS_COLUMNS);
+    conn.prepare(&query).unwrap();
+    conn
+      .prepare(r#"SELECT snapshot, "group", target, edit_message_id, not_before FROM outbox"#)
+      .unwrap();
+    conn.prepare("SELECT target FROM deliveries").unwrap();
+    conn.prepare("SELECT count FROM command_usage").unwrap();
+  }
+
+  #[test]
+  fn newer_schema() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn
+      .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+      .unwrap();
+    assert!(matches!(SqliteStore::with_connection(conn), Err(BotError::UnsupportedSchema { .. })));
+  }
+
+  #[tokio::test]
+  async fn get_or_new() {
+    check_get_or_new(&store()).await;
+  }
+
+  #[tokio::test]
+  async fn toggle() {
+    check_toggle(&store()).await;
+  }
+
+  #[tokio::test]
+  async fn notifiables() {
+    check_notifiables(&store()).await;
+  }
+
+  #[tokio::test]
+  async fn touch() {
+    check_touch(&store()).await;
+  }
+
+  #[tokio::test]
+  async fn supersede() {
+    check_supersede(&store()).await;
+  }
+
+  #[tokio::test]
+  async fn delete() {
+    check_delete(&store()).await;
+  }
+}
@@ -1,4 +1,6 @@
-use std::str::FromStr;
+use std::{net::SocketAddr, str::FromStr};
+
+use crate::db::StorageKind;
 
 macro_rules! env_var {
   ($var_name: ident, $env_name: literal) => {
@@ -14,7 +16,21 @@ env_var!(API_HOST);
 env_var!(TELOXIDE_TOKEN);
 
 env_var!(DEV_ID);
+env_var!(DEV_DELIVERY_REPORTS);
+
+env_var!(QUIET_HOURS);
+env_var!(QUIET_HOURS_UTC_OFFSET);
+
+env_var!(HTTP_LISTEN_ADDR);
+env_var!(PUSH_SECRET);
+env_var!(HEALTH_POLL_STALE_SECS);
+env_var!(HEALTH_UPDATE_STALE_SECS);
 
+env_var!(WEBHOOK_URL);
+env_var!(WEBHOOK_LISTEN_ADDR);
+env_var!(WEBHOOK_SECRET);
+
+env_var!(DB_KIND, "DATABASE_KIND");
 env_var!(DB_URL, "DATABASE_CONNECTION_URL");
 env_var!(DEFAULT_DB, "DEFAULT_DATABASE_NAME");
 
@@ -39,8 +55,32 @@ pub fn check_env_vars() {
 
   failed |= !check::<String>(TELOXIDE_TOKEN);
   failed |= !check::<String>(API_HOST);
-  failed |= !check::<String>(DB_URL);
-  failed |= !check::<String>(DEFAULT_DB);
+  match StorageKind::from_env() {
+    StorageKind::Mongo => {
+      failed |= !check::<String>(DB_URL);
+      failed |= !check::<String>(DEFAULT_DB);
+    }
+    #[cfg(feature = "sqlite")]
+    StorageKind::Sqlite => failed |= !check::<String>(DB_URL),
+    StorageKind::Memory => (),
+  }
+
+  match (var(HTTP_LISTEN_ADDR).is_some(), var(PUSH_SECRET).is_some()) {
+    (true, _) => {
+      failed |= !check::<SocketAddr>(HTTP_LISTEN_ADDR);
+      for threshold in [HEALTH_POLL_STALE_SECS, HEALTH_UPDATE_STALE_SECS] {
+        if var(threshold).is_some() {
+          failed |= !check::<i64>(threshold);
+        }
+      }
+    }
+    (false, true) => warn!("Var {} is set, but {} is not, pushed updates are disabled", PUSH_SECRET, HTTP_LISTEN_ADDR),
+    (false, false) => (),
+  }
+
+  if var(WEBHOOK_URL).is_some() {
+    failed |= !check::<SocketAddr>(WEBHOOK_LISTEN_ADDR);
+  }
 
   failed.then(|| {
     error!("Not all .env args are set");
@@ -3,6 +3,8 @@ use teloxide::dispatching::dialogue::InMemStorageError;
 use thiserror::Error;
 
 use crate::db::MongoError;
+#[cfg(feature = "sqlite")]
+use crate::db::SqliteError;
 
 pub trait ReadableError {
   fn readable(&self) -> String;
@@ -19,6 +21,12 @@ pub enum BotError {
   #[error("mongo-db error: {0}")]
   MongoError(String),
 
+  #[error("user-id {0} not found")]
+  UserNotFound(i64),
+
+  #[error("database schema v{found} is newer than the latest known v{known}")]
+  UnsupportedSchema { found: i32, known: i32 },
+
   #[error("teloxide-api error: {0}")]
   TeloxideApiError(teloxide::ApiError),
 
@@ -27,6 +35,16 @@ pub enum BotError {
 
   #[error("storage-error: {0}")]
   TeloxideInMemStorageError(InMemStorageError),
+
+  #[error("teloxide-download error: {0}")]
+  TeloxideDownloadError(teloxide::DownloadError),
+
+  #[error("backup error: {0}")]
+  BackupError(String),
+
+  #[cfg(feature = "sqlite")]
+  #[error("sqlite error: {0}")]
+  SqliteError(String),
 }
 
 impl ReadableError for BotError {
@@ -39,9 +57,17 @@ impl ReadableError for BotError {
       }
       BotError::ApiError(err, desc) => format!("Ошибка API 😓\nПричина: {err}.\nОписание: {desc}"),
       BotError::MongoError(err) => format!("Ошибка MongoDB 😓.\nСообщение: {err}"),
+      BotError::UserNotFound(_) => "Тебя нет в базе 🤔\nНажми /start".into(),
+      BotError::UnsupportedSchema { found, known } => {
+        format!("Схема базы данных v{found} новее поддерживаемой v{known} 😓")
+      }
       BotError::TeloxideApiError(err) => format!("Ошибка Teloxide API 😓.\nСообщение: {err}"),
       BotError::TeloxideRequestError(err) => format!("Ошибка Teloxide Request 😓.\nСообщение: {err}"),
       BotError::TeloxideInMemStorageError(err) => format!("Ошибка InMemStorage 😓.\nСообщение: {err}"),
+      BotError::TeloxideDownloadError(err) => format!("Ошибка загрузки файла 😓.\nСообщение: {err}"),
+      BotError::BackupError(err) => format!("Ошибка бэкапа 😓.\nСообщение: {err}"),
+      #[cfg(feature = "sqlite")]
+      BotError::SqliteError(err) => format!("Ошибка SQLite 😓.\nСообщение: {err}"),
     }
   }
 }
@@ -76,8 +102,27 @@ impl From<InMemStorageError> for BotError {
   }
 }
 
+impl From<teloxide::DownloadError> for BotError {
+  fn from(err: teloxide::DownloadError) -> Self {
+    Self::TeloxideDownloadError(err)
+  }
+}
+
+impl From<serde_json::Error> for BotError {
+  fn from(err: serde_json::Error) -> Self {
+    Self::BackupError(err.to_string())
+  }
+}
+
 impl From<MongoError> for BotError {
   fn from(err: MongoError) -> Self {
     Self::MongoError(err.to_string())
   }
 }
+
+#[cfg(feature = "sqlite")]
+impl From<SqliteError> for BotError {
+  fn from(err: SqliteError) -> Self {
+    Self::SqliteError(err.to_string())
+  }
+}
@@ -7,11 +7,16 @@ extern crate log;
 #[macro_use]
 extern crate lazy_static;
 
+mod api;
+mod backup;
 mod bot;
 mod db;
 mod env;
 mod error;
+mod metrics;
 mod poller;
+mod quiet_hours;
+mod server;
 
 #[tokio::main]
 async fn main() {
@@ -19,11 +24,22 @@ async fn main() {
   pretty_env_logger::init();
   env::check_env_vars();
 
-  let mongo = db::MongoPool::init().await.expect("Couldn't connect to database");
+  let db = db::connect().await.expect("Couldn't connect to database");
+
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  if !args.is_empty() {
+    if let Err(err) = backup::run_cli(&db, &args).await {
+      error!("{}", err);
+      std::process::exit(1);
+    }
+    return;
+  }
+
   let bot = Bot::from_env();
 
-  let mut poller = Poller::new(bot.clone(), mongo.clone());
+  let events = server::start(db.clone());
+  let mut poller = Poller::new(bot.clone(), db.clone(), events);
   tokio::spawn(async move { poller.run().await });
 
-  bot::start(bot, mongo).await
+  bot::start(bot, db).await
 }
@@ -0,0 +1,141 @@
+use std::{collections::BTreeMap, fmt::Debug, fmt::Write, future::Future, sync::Mutex, time::Instant};
+
+/// Latency buckets in seconds, from a cached storage read to a slow api call
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+lazy_static! {
+  pub static ref COMMANDS: CounterVec = CounterVec::new("maiq_bot_commands_total", "Dispatched commands", &["command"]);
+  pub static ref CALLBACKS: CounterVec = CounterVec::new("maiq_bot_callbacks_total", "Dispatched callback queries", &["kind"]);
+  pub static ref API_LATENCY: HistogramVec =
+    HistogramVec::new("maiq_bot_api_duration_seconds", "Latency of maiq api calls", &["function"]);
+  pub static ref POLL_ERRORS: CounterVec = CounterVec::new("maiq_bot_poll_errors_total", "Failed api polls", &[]);
+  pub static ref NOTIFICATIONS: CounterVec =
+    CounterVec::new("maiq_bot_notifications_total", "Notifications by group and outcome", &["group", "outcome"]);
+  pub static ref STORAGE_LATENCY: HistogramVec =
+    HistogramVec::new("maiq_bot_storage_duration_seconds", "Latency of storage operations", &["operation"]);
+}
+
+pub struct CounterVec {
+  name: &'static str,
+  help: &'static str,
+  labels: &'static [&'static str],
+  values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+pub struct HistogramVec {
+  name: &'static str,
+  help: &'static str,
+  labels: &'static [&'static str],
+  values: Mutex<BTreeMap<Vec<String>, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+  /// Not cumulative, summed up on render
+  buckets: Vec<u64>,
+  sum: f64,
+  count: u64,
+}
+
+impl CounterVec {
+  fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+    Self { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+  }
+
+  pub fn inc(&self, labels: &[&str]) {
+    let key = labels.iter().map(|l| l.to_string()).collect();
+    *self.values.lock().unwrap().entry(key).or_default() += 1;
+  }
+
+  fn render(&self, out: &mut String) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} counter", self.name, self.help, self.name).unwrap();
+    for (values, count) in self.values.lock().unwrap().iter() {
+      writeln!(out, "{}{} {}", self.name, format_labels(self.labels, values, None), count).unwrap();
+    }
+  }
+}
+
+impl HistogramVec {
+  fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+    Self { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+  }
+
+  pub fn observe(&self, labels: &[&str], secs: f64) {
+    let key = labels.iter().map(|l| l.to_string()).collect();
+    let mut values = self.values.lock().unwrap();
+    let histogram = values.entry(key).or_default();
+    histogram.buckets.resize(LATENCY_BUCKETS.len(), 0);
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+      histogram.buckets[bucket] += 1;
+    }
+    histogram.sum += secs;
+    histogram.count += 1;
+  }
+
+  /// Awaits the future and observes how long it took.
+  pub async fn time<F: Future>(&self, labels: &[&str], f: F) -> F::Output {
+    let start = Instant::now();
+    let res = f.await;
+    self.observe(labels, start.elapsed().as_secs_f64());
+    res
+  }
+
+  fn render(&self, out: &mut String) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} histogram", self.name, self.help, self.name).unwrap();
+    for (values, histogram) in self.values.lock().unwrap().iter() {
+      let mut cumulative = 0;
+      for (le, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+        cumulative += count;
+        let labels = format_labels(self.labels, values, Some(&le.to_string()));
+        writeln!(out, "{}_bucket{} {}", self.name, labels, cumulative).unwrap();
+      }
+
+      let inf = format_labels(self.labels, values, Some("+Inf"));
+      writeln!(out, "{}_bucket{} {}", self.name, inf, histogram.count).unwrap();
+      let labels = format_labels(self.labels, values, None);
+      writeln!(out, "{}_sum{} {}", self.name, labels, histogram.sum).unwrap();
+      writeln!(out, "{}_count{} {}", self.name, labels, histogram.count).unwrap();
+    }
+  }
+}
+
+/// All metrics in Prometheus text format.
+pub fn render() -> String {
+  let mut out = String::new();
+  for counter in [&*COMMANDS, &*CALLBACKS, &*POLL_ERRORS, &*NOTIFICATIONS] {
+    counter.render(&mut out);
+  }
+  for histogram in [&*API_LATENCY, &*STORAGE_LATENCY] {
+    histogram.render(&mut out);
+  }
+  out
+}
+
+/// Name of an enum variant without its fields, to be used as a label.
+pub fn variant<T: Debug>(value: &T) -> String {
+  let name = format!("{:?}", value);
+  match name.find(|c: char| !c.is_alphanumeric() && c != '_') {
+    Some(end) => name[..end].to_string(),
+    None => name,
+  }
+}
+
+fn format_labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+  let mut labels: Vec<String> = names
+    .iter()
+    .zip(values)
+    .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+    .collect();
+  if let Some(le) = le {
+    labels.push(format!("le=\"{}\"", le));
+  }
+
+  match labels.is_empty() {
+    true => String::new(),
+    false => format!("{{{}}}", labels.join(",")),
+  }
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
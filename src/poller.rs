@@ -1,65 +1,351 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, fmt::Display, sync::RwLock, time::Duration};
 
-use chrono::{DateTime, NaiveTime, Utc};
-use maiq_api_wrapper as api;
+use chrono::{DateTime, Datelike, NaiveDate, Utc, Weekday};
 use maiq_shared::{utils::time::*, Fetch};
 use teloxide::Bot;
-use tokio::time::sleep;
+use tokio::{sync::mpsc::UnboundedReceiver, time::sleep};
 
-use crate::{bot::notifier::notify_update, db::MongoPool};
+use crate::{
+  api,
+  bot::notifier::{cleanup, notify_update, resume_outbox, run_deferred, send_to_dev},
+  db::Database,
+  metrics::POLL_ERRORS,
+  quiet_hours::{local_now, QuietHours},
+  server::UpdateEvent,
+};
+
+/// Consecutive failed polls after which the api is considered down
+const FAILURE_THRESHOLD: u32 = 3;
+const BACKOFF_BASE: Duration = Duration::from_secs(10);
+const BACKOFF_CAP: Duration = Duration::from_secs(10 * 60);
+
+lazy_static! {
+  pub static ref STATUS: RwLock<PollerStatus> = RwLock::new(PollerStatus::default());
+}
+
+/// Circuit breaker around api polling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Breaker {
+  /// The api works
+  #[default]
+  Closed,
+  /// The api is down, polls are made only after backoff
+  Open,
+  /// The api was down, the next poll decides whether it has recovered
+  HalfOpen,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PollerStatus {
+  pub breaker: Breaker,
+  pub failures: u32,
+  pub down_since: Option<DateTime<Utc>>,
+  pub last_error: Option<String>,
+  pub retry_at: Option<DateTime<Utc>>,
+  /// Last successful poll
+  pub last_poll: Option<DateTime<Utc>>,
+  /// When the poller is going to poll again, it's stuck if this has passed long ago
+  pub next_poll: Option<DateTime<Utc>>,
+}
 
 pub struct Poller {
   bot: Bot,
-  mongo: MongoPool,
+  db: Database,
+  quiet_hours: Option<QuietHours>,
+  queued: Option<Queued>,
+  /// Changes pushed to the http server, polling is kept as a fallback
+  events: Option<UnboundedReceiver<UpdateEvent>>,
+}
+
+/// Changes detected during quiet hours, by the date of the timetable they are about
+#[derive(Default)]
+struct Queued {
+  changes: BTreeMap<NaiveDate, Vec<String>>,
+}
+
+impl Queued {
+  fn push(&mut self, date: NaiveDate, today: Vec<String>, next: Vec<String>) {
+    for (date, changes) in [(date, today), (next_day(date), next)] {
+      if !changes.is_empty() {
+        merge(self.changes.entry(date).or_default(), changes);
+      }
+    }
+  }
+
+  /// Changes about past dates are dropped, their timetables are not fetched anymore.
+  fn merge_into(self, date: NaiveDate, today: &mut Vec<String>, next: &mut Vec<String>) {
+    for (day, changes) in self.changes {
+      match day {
+        day if day == date => merge(today, changes),
+        day if day == next_day(date) => merge(next, changes),
+        day => info!("Dropped queued changes for {}: {:?}", day, changes),
+      }
+    }
+  }
 }
 
 impl Poller {
-  pub fn new(bot: Bot, mongo: MongoPool) -> Self {
-    Self { bot, mongo }
+  pub fn new(bot: Bot, db: Database, events: Option<UnboundedReceiver<UpdateEvent>>) -> Self {
+    let quiet_hours = QuietHours::from_env();
+    match quiet_hours {
+      Some(q) => info!("Quiet hours are {}", q),
+      None => info!("Quiet hours are disabled"),
+    }
+
+    Self { bot, db, quiet_hours, queued: None, events }
   }
 
   pub async fn run(&mut self) {
-    loop {
-      if now().time() < NaiveTime::from_hms_opt(6, 0, 0).unwrap() {
-        let wait_s = 7 * 60 * 60 - (now().timestamp() - now_date().timestamp()) as u64;
-        info!("Sleeping due to the night for {}s", wait_s);
-        sleep(Duration::from_secs(wait_s)).await;
-      };
+    if let Err(err) = cleanup(&self.db).await {
+      error!("Couldn't clean up the storage: {}", err);
+    }
 
+    if let Err(err) = resume_outbox(&self.bot, &self.db).await {
+      error!("Couldn't resume undelivered messages: {}", err);
+    }
+
+    tokio::spawn(run_deferred(self.bot.clone(), self.db.clone()));
+
+    loop {
       let poll = match api::poll().await {
-        Ok(p) => p,
+        Ok(p) => {
+          self.on_success().await;
+          p
+        }
         Err(err) => {
-          error!("Couldn't make a poll request: {}: {}", err.cause, err.desc);
-          sleep(Duration::from_secs(10)).await;
+          let backoff = self.on_failure(format!("{}: {}", err.cause, err.desc)).await;
+          self.idle(backoff).await;
+          STATUS.write().unwrap().breaker.half_open();
           continue;
         }
       };
 
-      self.notify_if_need(poll.today_changes, Fetch::Today).await;
-      self.notify_if_need(poll.next_changes, Fetch::Next).await;
-      self.wait(poll.next_update).await;
+      let quiet_left = self.quiet_left();
+      self.handle(poll.today_changes, poll.next_changes).await;
+      self.wait(poll.next_update, quiet_left).await;
+    }
+  }
+
+  /// Changes from both polls and pushes go here.
+  async fn handle(&mut self, today: Vec<String>, next: Vec<String>) {
+    match self.quiet_left() {
+      Some(_) => self.queue(today, next),
+      None => {
+        let (today, next) = self.take_queued(today, next);
+        self.notify_if_need(today, Fetch::Today).await;
+        self.notify_if_need(next, Fetch::Next).await;
+      }
+    }
+  }
+
+  async fn on_success(&self) {
+    let recovered = {
+      let mut status = STATUS.write().unwrap();
+      let recovered = match status.breaker {
+        Breaker::Closed => None,
+        _ => status.down_since,
+      };
+      *status = PollerStatus { last_poll: Some(now()), ..Default::default() };
+      recovered
+    };
+
+    if let Some(since) = recovered {
+      let downtime = now().signed_duration_since(since);
+      info!("Api has recovered after {}m", downtime.num_minutes());
+      send_to_dev(&self.bot, format!("✅ Api снова доступно, было недоступно {} мин", downtime.num_minutes())).await;
+    }
+  }
+
+  /// Returns how long to wait before the next poll.
+  async fn on_failure(&self, err: String) -> Duration {
+    let (opened, failures, backoff) = {
+      let mut status = STATUS.write().unwrap();
+      POLL_ERRORS.inc(&[]);
+      status.failures += 1;
+      status.down_since.get_or_insert_with(now);
+      status.last_error = Some(err.clone());
+
+      let opened = status.breaker == Breaker::Closed && status.failures >= FAILURE_THRESHOLD;
+      if status.failures >= FAILURE_THRESHOLD {
+        status.breaker = Breaker::Open;
+      }
+
+      let backoff = backoff(status.failures);
+      status.retry_at = Some(now() + chrono::Duration::from_std(backoff).unwrap());
+      status.next_poll = status.retry_at;
+      (opened, status.failures, backoff)
+    };
+
+    match (opened, failures < FAILURE_THRESHOLD) {
+      (true, _) => {
+        error!("Api is down after {} failed polls: {}", failures, err);
+        send_to_dev(&self.bot, format!("🔥 Api недоступно после {} попыток\n<code>{}</code>", failures, err)).await;
+      }
+      (false, true) => warn!("Couldn't make a poll request: {}, retry in {}s", err, backoff.as_secs()),
+      (false, false) => debug!("Api is still down: {}, retry in {}s", err, backoff.as_secs()),
+    }
+
+    backoff
+  }
+
+  fn quiet_left(&self) -> Option<chrono::Duration> {
+    self.quiet_hours.and_then(|q| q.remaining(local_now().time()))
+  }
+
+  fn queue(&mut self, today: Vec<String>, next: Vec<String>) {
+    let queued = self.queued.get_or_insert_with(Queued::default);
+    queued.push(local_now().date(), today, next);
+    if !queued.changes.is_empty() {
+      info!("Quiet hours, queued changes: {:?}", queued.changes);
     }
   }
 
+  /// Merges queued changes into the polled ones by the date they are about, the day may have changed since queueing.
+  fn take_queued(&mut self, mut today: Vec<String>, mut next: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let queued = match self.queued.take() {
+      Some(queued) => queued,
+      None => return (today, next),
+    };
+
+    queued.merge_into(local_now().date(), &mut today, &mut next);
+    info!("Quiet hours are over, sending changes: today {:?}, next {:?}", today, next);
+    (today, next)
+  }
+
   async fn notify_if_need(&self, changes: Vec<String>, fetch: Fetch) {
     if changes.is_empty() {
       return;
     }
 
     if let Ok(snapshot) = api::latest(fetch).await {
-      if let Err(err) = notify_update(&self.bot, &self.mongo, snapshot, changes).await {
+      if let Err(err) = notify_update(&self.bot, &self.db, snapshot, fetch, changes).await {
         error!("An error occured while notifying users: {}", err);
       }
     }
   }
 
-  async fn wait(&self, next_update: DateTime<Utc>) {
-    let wait = next_update
-      .signed_duration_since(now())
-      .num_milliseconds()
-      .clamp(1000 * 10, 1000 * 24 * 60 * 60) as u64;
+  /// Wakes up at the next update or at the end of quiet hours, whichever is first.
+  async fn wait(&mut self, next_update: DateTime<Utc>, quiet_left: Option<chrono::Duration>) {
+    let mut wait = next_update.signed_duration_since(now());
+    if let Some(left) = quiet_left {
+      wait = wait.min(left);
+    }
+
+    let wait = wait.num_milliseconds().clamp(1000 * 10, 1000 * 24 * 60 * 60) as u64;
+    STATUS.write().unwrap().next_poll = Some(now() + chrono::Duration::milliseconds(wait as i64));
 
     // info!("Sleeping for {}s in awaiting of next update", wait as f32 / 1000f32);
-    sleep(Duration::from_millis(wait)).await;
+    self.idle(Duration::from_millis(wait)).await;
+  }
+
+  /// Sleeps, handling pushed changes in the meantime.
+  async fn idle(&mut self, duration: Duration) {
+    let timer = sleep(duration);
+    tokio::pin!(timer);
+
+    loop {
+      let event = tokio::select! {
+        _ = &mut timer => return,
+        Some(event) = next_event(&mut self.events) => event,
+      };
+
+      self.handle(event.today_changes, event.next_changes).await;
+    }
+  }
+}
+
+impl Breaker {
+  fn half_open(&mut self) {
+    if *self == Breaker::Open {
+      *self = Breaker::HalfOpen
+    }
+  }
+}
+
+impl Display for Breaker {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let state = match self {
+      Breaker::Closed => "closed",
+      Breaker::Open => "open",
+      Breaker::HalfOpen => "half-open",
+    };
+    write!(f, "{}", state)
+  }
+}
+
+fn backoff(failures: u32) -> Duration {
+  let exp = BACKOFF_BASE
+    .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+    .min(BACKOFF_CAP);
+  exp + Duration::from_millis(fastrand::u64(0..=exp.as_millis() as u64 / 4))
+}
+
+/// The date `Fetch::Next` is about, there are no lessons on Sunday.
+fn next_day(date: NaiveDate) -> NaiveDate {
+  let next = date.succ_opt().unwrap();
+  match next.weekday() {
+    Weekday::Sun => next.succ_opt().unwrap(),
+    _ => next,
+  }
+}
+
+fn merge(into: &mut Vec<String>, changes: Vec<String>) {
+  for group in changes {
+    if !into.contains(&group) {
+      into.push(group);
+    }
+  }
+}
+
+async fn next_event(events: &mut Option<UnboundedReceiver<UpdateEvent>>) -> Option<UpdateEvent> {
+  match events {
+    Some(events) => events.recv().await,
+    None => std::future::pending().await,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn groups(names: &[&str]) -> Vec<String> {
+    names.iter().map(|n| n.to_string()).collect()
+  }
+
+  fn queued(date: NaiveDate) -> Queued {
+    let mut queued = Queued::default();
+    queued.push(date, groups(&["A", "B"]), groups(&["C", "D"]));
+    queued
+  }
+
+  #[test]
+  fn take_queued_same_day() {
+    let date = NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+    let (mut today, mut next) = (groups(&["B", "E"]), groups(&[]));
+    queued(date).merge_into(date, &mut today, &mut next);
+    assert_eq!(today, groups(&["B", "E", "A"]));
+    assert_eq!(next, groups(&["C", "D"]));
+  }
+
+  #[test]
+  fn take_queued_next_day() {
+    let date = NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+    let (mut today, mut next) = (groups(&["D"]), groups(&["F"]));
+    queued(date).merge_into(date.succ_opt().unwrap(), &mut today, &mut next);
+    assert_eq!(today, groups(&["D", "C"]));
+    assert_eq!(next, groups(&["F"]));
+
+    // Queued on Saturday night, `Next` was about Monday and still is on Sunday
+    let saturday = NaiveDate::from_ymd_opt(2023, 3, 4).unwrap();
+    let (mut today, mut next) = (groups(&[]), groups(&["F"]));
+    queued(saturday).merge_into(saturday.succ_opt().unwrap(), &mut today, &mut next);
+    assert!(today.is_empty());
+    assert_eq!(next, groups(&["F", "C", "D"]));
+
+    let monday = NaiveDate::from_ymd_opt(2023, 3, 6).unwrap();
+    let (mut today, mut next) = (groups(&[]), groups(&[]));
+    queued(saturday).merge_into(monday, &mut today, &mut next);
+    assert_eq!(today, groups(&["C", "D"]));
+    assert!(next.is_empty());
   }
 }
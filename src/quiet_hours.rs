@@ -0,0 +1,140 @@
+use std::{fmt::Display, str::FromStr};
+
+use chrono::{Duration, NaiveDateTime, NaiveTime, Utc};
+use maiq_shared::utils::time::now;
+use serde::{Deserialize, Serialize};
+
+use crate::env;
+
+/// Daily window, `start` may be later than `end` for windows across midnight. The window is empty if they are equal.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+  pub start: NaiveTime,
+  pub end: NaiveTime,
+}
+
+impl QuietHours {
+  pub fn new(start: NaiveTime, end: NaiveTime) -> Self {
+    Self { start, end }
+  }
+
+  /// `QUIET_HOURS` as `HH:MM-HH:MM`, 00:00-06:00 if not set, `off` disables them.
+  pub fn from_env() -> Option<Self> {
+    match env::var(env::QUIET_HOURS) {
+      Some(x) if x.trim() == "off" => None,
+      Some(x) => Some(
+        x.parse()
+          .unwrap_or_else(|_| panic!("Invalid quiet hours `{}`, expected HH:MM-HH:MM", x)),
+      ),
+      None => Some(Self::new(NaiveTime::from_hms_opt(0, 0, 0).unwrap(), NaiveTime::from_hms_opt(6, 0, 0).unwrap())),
+    }
+  }
+
+  pub fn contains(&self, time: NaiveTime) -> bool {
+    match self.start <= self.end {
+      true => self.start <= time && time < self.end,
+      false => self.start <= time || time < self.end,
+    }
+  }
+
+  /// Time left until the end of the window, `None` if `time` is outside of it.
+  pub fn remaining(&self, time: NaiveTime) -> Option<Duration> {
+    if !self.contains(time) {
+      return None;
+    }
+
+    match self.end - time {
+      left if left < Duration::zero() => Some(left + Duration::days(1)),
+      left => Some(left),
+    }
+  }
+}
+
+impl FromStr for QuietHours {
+  type Err = chrono::ParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (start, end) = s.split_once('-').unwrap_or((s, ""));
+    Ok(Self::new(NaiveTime::parse_from_str(start.trim(), "%H:%M")?, NaiveTime::parse_from_str(end.trim(), "%H:%M")?))
+  }
+}
+
+impl Display for QuietHours {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+  }
+}
+
+/// Local time for quiet hours: UTC shifted by `QUIET_HOURS_UTC_OFFSET` hours if set, the api time otherwise.
+pub fn local_now() -> NaiveDateTime {
+  match env::parse_var::<i64>(env::QUIET_HOURS_UTC_OFFSET) {
+    Some(offset) => (Utc::now() + Duration::hours(offset)).naive_utc(),
+    None => now().naive_utc(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn time(h: u32, m: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(h, m, 0).unwrap()
+  }
+
+  fn window(s: &str) -> QuietHours {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn contains_within_day() {
+    let quiet = window("01:00-06:00");
+    assert!(quiet.contains(time(3, 0)));
+    assert!(!quiet.contains(time(0, 30)));
+    assert!(!quiet.contains(time(12, 0)));
+    assert_eq!(quiet.remaining(time(3, 0)), Some(Duration::hours(3)));
+    assert_eq!(quiet.remaining(time(12, 0)), None);
+  }
+
+  #[test]
+  fn contains_across_midnight() {
+    let quiet = window("22:00-07:00");
+    assert!(quiet.contains(time(23, 0)));
+    assert!(quiet.contains(time(3, 0)));
+    assert!(!quiet.contains(time(12, 0)));
+    assert!(!quiet.contains(time(21, 59)));
+    assert_eq!(quiet.remaining(time(23, 0)), Some(Duration::hours(8)));
+    assert_eq!(quiet.remaining(time(3, 0)), Some(Duration::hours(4)));
+  }
+
+  #[test]
+  fn empty_window() {
+    let quiet = window("05:00-05:00");
+    for t in [time(0, 0), time(5, 0), time(12, 0), time(23, 59)] {
+      assert!(!quiet.contains(t));
+      assert_eq!(quiet.remaining(t), None);
+    }
+  }
+
+  #[test]
+  fn boundaries() {
+    let quiet = window("22:00-07:00");
+    assert!(quiet.contains(time(22, 0)));
+    assert!(!quiet.contains(time(7, 0)));
+    assert_eq!(quiet.remaining(time(22, 0)), Some(Duration::hours(9)));
+    assert_eq!(quiet.remaining(time(7, 0)), None);
+
+    let quiet = window("00:00-06:00");
+    assert!(quiet.contains(time(0, 0)));
+    assert!(!quiet.contains(time(6, 0)));
+    assert!(quiet.contains(time(5, 59)));
+  }
+
+  #[test]
+  fn parse() {
+    assert_eq!(window(" 22:00 - 07:30 "), QuietHours::new(time(22, 0), time(7, 30)));
+    assert_eq!(window("22:00-07:30").to_string(), "22:00-07:30");
+    for invalid in ["", "22:00", "22:00-", "-07:00", "25:00-07:00", "22:00-07:60", "10pm-7am", "22:00-07:00-08:00"] {
+      assert!(invalid.parse::<QuietHours>().is_err(), "`{}` should be rejected", invalid);
+    }
+  }
+}
@@ -0,0 +1,191 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+  body::Bytes,
+  extract::State,
+  http::{HeaderMap, StatusCode},
+  routing::{get, post},
+  Json, Router,
+};
+use chrono::{DateTime, Utc};
+use maiq_shared::utils::time::now;
+use openssl::{memcmp, sha::sha256};
+use serde::{Deserialize, Serialize};
+use tokio::{
+  sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+  time::timeout,
+};
+
+use crate::{
+  bot::LAST_UPDATE,
+  db::Database,
+  env, metrics,
+  poller::{Breaker, STATUS},
+};
+
+const SECRET_HEADER: &str = "X-Maiq-Secret";
+
+/// Default for `HEALTH_POLL_STALE_SECS`
+const POLL_STALE_SECS: i64 = 15 * 60;
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+  static ref STARTED: DateTime<Utc> = now();
+}
+
+/// Same changes as in `api::poll()`
+#[derive(Deserialize, Debug, Default)]
+pub struct UpdateEvent {
+  #[serde(default)]
+  pub today_changes: Vec<String>,
+  #[serde(default)]
+  pub next_changes: Vec<String>,
+}
+
+/// Same report for both `/healthz` and `/readyz`, they differ only in the status code.
+#[derive(Serialize, Debug)]
+struct Health {
+  /// The poller isn't stuck
+  alive: bool,
+  /// Alive, the api and the database are reachable and updates are fresh
+  ready: bool,
+  database: String,
+  breaker: String,
+  last_poll: Option<DateTime<Utc>>,
+  next_poll: Option<DateTime<Utc>>,
+  last_update: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone)]
+struct PushState {
+  secret: Arc<String>,
+  events: UnboundedSender<UpdateEvent>,
+}
+
+/// Starts the http server with health checks and metrics if `HTTP_LISTEN_ADDR` is set. Returns pushed change events if `PUSH_SECRET` is set as well.
+pub fn start(db: Database) -> Option<UnboundedReceiver<UpdateEvent>> {
+  let addr: SocketAddr = env::parse_var(env::HTTP_LISTEN_ADDR)?;
+  lazy_static::initialize(&STARTED);
+  let mut router = Router::new()
+    .route("/healthz", get(healthz))
+    .route("/readyz", get(readyz))
+    .route("/metrics", get(render_metrics))
+    .with_state(db);
+  let mut events = None;
+
+  if let Some(secret) = env::var(env::PUSH_SECRET) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let state = PushState { secret: Arc::new(secret), events: tx };
+    router = router.merge(Router::new().route("/updates", post(push_update)).with_state(state));
+    events = Some(rx);
+  }
+
+  info!("Listening on {}, pushed updates are {}", addr, if events.is_some() { "enabled" } else { "disabled" });
+  tokio::spawn(async move {
+    if let Err(err) = axum::Server::bind(&addr).serve(router.into_make_service()).await {
+      error!("Http server has stopped: {}", err);
+    }
+  });
+
+  events
+}
+
+async fn push_update(State(state): State<PushState>, headers: HeaderMap, body: Bytes) -> StatusCode {
+  let secret = headers
+    .get(SECRET_HEADER)
+    .and_then(|s| s.to_str().ok())
+    .unwrap_or_default();
+  if !secret_matches(secret, &state.secret) {
+    warn!("Rejected pushed update with invalid secret");
+    return StatusCode::UNAUTHORIZED;
+  }
+
+  let event: UpdateEvent = match serde_json::from_slice(&body) {
+    Ok(event) => event,
+    Err(err) => {
+      warn!("Rejected malformed pushed update: {}", err);
+      return StatusCode::BAD_REQUEST;
+    }
+  };
+
+  info!("Pushed changes: today {:?}, next {:?}", event.today_changes, event.next_changes);
+  match state.events.send(event) {
+    Ok(_) => StatusCode::ACCEPTED,
+    Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+  }
+}
+
+async fn healthz(State(db): State<Database>) -> (StatusCode, Json<Health>) {
+  let health = Health::check(&db).await;
+  (status_code(health.alive), Json(health))
+}
+
+async fn readyz(State(db): State<Database>) -> (StatusCode, Json<Health>) {
+  let health = Health::check(&db).await;
+  (status_code(health.ready), Json(health))
+}
+
+async fn render_metrics() -> String {
+  metrics::render()
+}
+
+fn status_code(ok: bool) -> StatusCode {
+  match ok {
+    true => StatusCode::OK,
+    false => StatusCode::SERVICE_UNAVAILABLE,
+  }
+}
+
+impl Health {
+  async fn check(db: &Database) -> Self {
+    let status = STATUS.read().unwrap().clone();
+    let last_update = *LAST_UPDATE.read().unwrap();
+    let database = match timeout(PING_TIMEOUT, db.ping()).await {
+      Ok(Ok(())) => Ok(()),
+      Ok(Err(err)) => Err(err.to_string()),
+      Err(_) => Err("ping timed out".to_string()),
+    };
+
+    let now = now();
+    let poll_stale = chrono::Duration::seconds(env::parse_var(env::HEALTH_POLL_STALE_SECS).unwrap_or(POLL_STALE_SECS));
+    let alive = !matches!(status.next_poll, Some(at) if now >= at + poll_stale);
+    // After a successful poll the poller may sleep until the next update or the end of quiet hours, that's fine while
+    // it's not overdue. Staleness only matters once the api is failing.
+    let is_poll_fresh = match status.breaker {
+      Breaker::Closed => status.last_poll.is_some() || now < *STARTED + poll_stale,
+      Breaker::Open | Breaker::HalfOpen => now < status.last_poll.unwrap_or(*STARTED) + poll_stale,
+    };
+    let is_update_fresh = match env::parse_var::<i64>(env::HEALTH_UPDATE_STALE_SECS) {
+      Some(secs) => now < last_update.unwrap_or(*STARTED) + chrono::Duration::seconds(secs),
+      None => true,
+    };
+
+    Self {
+      alive,
+      ready: alive && is_poll_fresh && is_update_fresh && database.is_ok(),
+      database: database.err().unwrap_or_else(|| "ok".into()),
+      breaker: status.breaker.to_string(),
+      last_poll: status.last_poll,
+      next_poll: status.next_poll,
+      last_update,
+    }
+  }
+}
+
+/// Constant-time comparison of fixed-length digests, so neither the secret nor its length can be guessed by response timings.
+fn secret_matches(given: &str, secret: &str) -> bool {
+  memcmp::eq(&sha256(given.as_bytes()), &sha256(secret.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn secret() {
+    assert!(secret_matches("s3cret", "s3cret"));
+    assert!(!secret_matches("s3creT", "s3cret"));
+    assert!(!secret_matches("s3cre", "s3cret"));
+    assert!(!secret_matches("", "s3cret"));
+  }
+}